@@ -0,0 +1,859 @@
+//! Grid carbon intensity: live providers, imported hourly profiles and
+//! forecasts for scheduling.
+
+use super::*;
+
+// ============================================
+// Intensity Providers
+// ============================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct IntensityReading {
+    pub(crate) provider: String,
+    pub(crate) zone: String,
+    pub(crate) gco2_kwh: f64,
+    /// When the figure is valid, as reported by the provider.
+    pub(crate) timestamp: String,
+    pub(crate) fetched_at: String,
+    /// Set when the provider couldn't be reached and an expired cached
+    /// reading was used instead.
+    #[serde(default)]
+    pub(crate) stale: bool,
+}
+
+trait IntensityProvider {
+    fn name(&self) -> &'static str;
+    fn current(&self, zone: &str) -> Result<IntensityReading, String>;
+    /// Hourly (or finer) forecast starting around now.
+    fn forecast(&self, zone: &str) -> Result<Vec<IntensityPoint>, String>;
+}
+
+/// Minimal blocking HTTP GET, so providers can be exercised against a fake.
+/// `query` pairs are percent-encoded by the backend.
+trait HttpBackend {
+    fn get(&self, url: &str, query: &[(&str, &str)], headers: &[(&str, &str)]) -> Result<String, String>;
+}
+
+struct UreqBackend {
+    agent: ureq::Agent,
+}
+
+impl UreqBackend {
+    fn new() -> Self {
+        Self::with_timeout(Duration::from_secs(5))
+    }
+
+    fn with_timeout(timeout: Duration) -> Self {
+        let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        UreqBackend { agent }
+    }
+}
+
+impl HttpBackend for UreqBackend {
+    fn get(&self, url: &str, query: &[(&str, &str)], headers: &[(&str, &str)]) -> Result<String, String> {
+        let mut request = self.agent.get(url);
+        for (name, value) in query {
+            request = request.query(name, value);
+        }
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let response = request.call().map_err(|e| e.to_string())?;
+        response.into_string().map_err(|e| e.to_string())
+    }
+}
+
+struct StaticIntensityProvider;
+
+impl StaticIntensityProvider {
+    fn reading(&self, zone: &str) -> IntensityReading {
+        let intensity = get_carbon_intensity_map()
+            .get(zone)
+            .map(|e| e.gco2_kwh)
+            .unwrap_or(475.0);
+        let now = Utc::now().to_rfc3339();
+        IntensityReading {
+            provider: self.name().to_string(),
+            zone: zone.to_string(),
+            gco2_kwh: intensity,
+            timestamp: now.clone(),
+            fetched_at: now,
+            stale: false,
+        }
+    }
+}
+
+impl IntensityProvider for StaticIntensityProvider {
+    fn name(&self) -> &'static str { "static" }
+
+    fn current(&self, zone: &str) -> Result<IntensityReading, String> {
+        Ok(self.reading(zone))
+    }
+
+    fn forecast(&self, _zone: &str) -> Result<Vec<IntensityPoint>, String> {
+        Err("The static table has no forecast".to_string())
+    }
+}
+
+struct HttpIntensityProvider<B: HttpBackend> {
+    backend: B,
+    kind: IntensityProviderKind,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl<B: HttpBackend> HttpIntensityProvider<B> {
+    fn parse(&self, zone: &str, body: &str) -> Result<IntensityReading, String> {
+        let json: serde_json::Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+        let (gco2_kwh, timestamp) = match self.kind {
+            IntensityProviderKind::ElectricityMaps => (
+                json["carbonIntensity"].as_f64(),
+                json["datetime"].as_str(),
+            ),
+            IntensityProviderKind::UkCarbonIntensity => {
+                let period = &json["data"][0];
+                let intensity = &period["intensity"];
+                (
+                    intensity["actual"].as_f64().or_else(|| intensity["forecast"].as_f64()),
+                    period["from"].as_str(),
+                )
+            }
+            IntensityProviderKind::Static => (None, None),
+        };
+        let gco2_kwh = gco2_kwh.ok_or_else(|| format!("{} response has no intensity", self.name()))?;
+        let fetched_at = Utc::now().to_rfc3339();
+        Ok(IntensityReading {
+            provider: self.name().to_string(),
+            zone: zone.to_string(),
+            gco2_kwh,
+            timestamp: timestamp.map(str::to_string).unwrap_or_else(|| fetched_at.clone()),
+            fetched_at,
+            stale: false,
+        })
+    }
+}
+
+impl<B: HttpBackend> IntensityProvider for HttpIntensityProvider<B> {
+    fn name(&self) -> &'static str {
+        match self.kind {
+            IntensityProviderKind::ElectricityMaps => "electricityMaps",
+            IntensityProviderKind::UkCarbonIntensity => "ukCarbonIntensity",
+            IntensityProviderKind::Static => "static",
+        }
+    }
+
+    fn current(&self, zone: &str) -> Result<IntensityReading, String> {
+        let base = self.base_url.trim_end_matches('/');
+        let (url, query) = match self.kind {
+            IntensityProviderKind::ElectricityMaps => (format!("{}/v3/carbon-intensity/latest", base), vec![("zone", zone)]),
+            _ => (format!("{}/intensity", base), Vec::new()),
+        };
+        let mut headers = vec![("Accept", "application/json")];
+        if let Some(token) = &self.token {
+            headers.push(("auth-token", token.as_str()));
+        }
+        let body = self.backend.get(&url, &query, &headers)?;
+        self.parse(zone, &body)
+    }
+
+    fn forecast(&self, zone: &str) -> Result<Vec<IntensityPoint>, String> {
+        let base = self.base_url.trim_end_matches('/');
+        let (url, query) = match self.kind {
+            IntensityProviderKind::ElectricityMaps => (format!("{}/v3/carbon-intensity/forecast", base), vec![("zone", zone)]),
+            _ => (format!("{}/intensity/{}/fw48h", base, Utc::now().format("%Y-%m-%dT%H:%MZ")), Vec::new()),
+        };
+        let mut headers = vec![("Accept", "application/json")];
+        if let Some(token) = &self.token {
+            headers.push(("auth-token", token.as_str()));
+        }
+        let body = self.backend.get(&url, &query, &headers)?;
+        let json: serde_json::Value = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+
+        let (list, value_of, time_of): (_, fn(&serde_json::Value) -> Option<f64>, &str) = match self.kind {
+            IntensityProviderKind::ElectricityMaps => (&json["forecast"], |p| p["carbonIntensity"].as_f64(), "datetime"),
+            _ => (&json["data"], |p| p["intensity"]["forecast"].as_f64(), "from"),
+        };
+        let mut points: Vec<IntensityPoint> = list
+            .as_array()
+            .ok_or_else(|| format!("{} response has no forecast", self.name()))?
+            .iter()
+            .filter_map(|p| {
+                let timestamp = p[time_of].as_str().and_then(parse_provider_time)?;
+                Some(IntensityPoint { timestamp, gco2_kwh: value_of(p)? })
+            })
+            .collect();
+        points.sort_by_key(|p| p.timestamp);
+        Ok(points)
+    }
+}
+
+/// Providers differ on whether seconds are included ("2018-01-20T12:00Z").
+fn parse_provider_time(raw: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(raw)
+        .ok()
+        .or_else(|| DateTime::parse_from_str(raw, "%Y-%m-%dT%H:%M%#z").ok())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn intensity_provider_from_settings(settings: &IntensityProviderSettings) -> Box<dyn IntensityProvider> {
+    if settings.kind == IntensityProviderKind::Static || settings.base_url.is_empty() {
+        return Box::new(StaticIntensityProvider);
+    }
+    Box::new(HttpIntensityProvider {
+        backend: UreqBackend::new(),
+        kind: settings.kind,
+        base_url: settings.base_url.clone(),
+        token: settings.token.clone(),
+    })
+}
+
+fn get_intensity_cache_path(app_handle: &tauri::AppHandle) -> Result<PathBuf, CarbonLintError> {
+    app_data_file(app_handle, "intensity_cache.json")
+}
+
+/// The cache is disposable, so an unreadable one just counts as empty.
+fn load_intensity_cache(app_handle: &tauri::AppHandle) -> HashMap<String, IntensityReading> {
+    get_intensity_cache_path(app_handle)
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Where `cached_or_fetched` got its reading from; only fetched readings
+/// are written back to the cache.
+enum CachedOrFetched {
+    Cached(IntensityReading),
+    Fetched(IntensityReading),
+}
+
+/// A fresh cached reading if there is one, otherwise a live fetch, otherwise
+/// the expired cached reading marked stale. `None` when the provider fails
+/// and nothing was cached.
+fn cached_or_fetched(
+    provider: &dyn IntensityProvider,
+    zone: &str,
+    cached: Option<IntensityReading>,
+    ttl_minutes: i64,
+    now: DateTime<Utc>,
+) -> Option<CachedOrFetched> {
+    let fresh = cached.as_ref().is_some_and(|cached| {
+        DateTime::parse_from_rfc3339(&cached.fetched_at)
+            .map(|t| now.signed_duration_since(t).num_minutes() < ttl_minutes)
+            .unwrap_or(false)
+    });
+    if fresh {
+        return cached.map(CachedOrFetched::Cached);
+    }
+    match provider.current(zone) {
+        Ok(reading) => Some(CachedOrFetched::Fetched(reading)),
+        Err(_) => cached.map(|cached| CachedOrFetched::Cached(IntensityReading { stale: true, ..cached })),
+    }
+}
+
+/// Current intensity for the configured region: a cached or live reading
+/// from the provider, otherwise the static table.
+pub(crate) fn resolve_intensity(app_handle: &tauri::AppHandle, settings: &AppSettings) -> IntensityReading {
+    let provider_settings = &settings.intensity_provider;
+    let provider = intensity_provider_from_settings(provider_settings);
+    let zone = provider_settings.zone.clone().unwrap_or_else(|| settings.region.clone());
+    if provider.name() == "static" {
+        return StaticIntensityProvider.reading(&settings.region);
+    }
+
+    let key = format!("{}:{}", provider.name(), zone);
+    let cached = load_intensity_cache(app_handle).remove(&key);
+    match cached_or_fetched(provider.as_ref(), &zone, cached, provider_settings.cache_ttl_minutes, Utc::now()) {
+        Some(CachedOrFetched::Cached(reading)) => reading,
+        Some(CachedOrFetched::Fetched(reading)) => {
+            // Re-read under the lock so entries written by another instance survive.
+            if let Ok(_lock) = DataDirLock::acquire(app_handle) {
+                let mut cache = load_intensity_cache(app_handle);
+                cache.insert(key, reading.clone());
+                if let (Ok(path), Ok(json)) = (get_intensity_cache_path(app_handle), serde_json::to_string_pretty(&cache)) {
+                    let _ = write_atomic(&path, json.as_bytes());
+                }
+            }
+            reading
+        }
+        None => StaticIntensityProvider.reading(&settings.region),
+    }
+}
+
+// ============================================
+// Hourly Profiles
+// ============================================
+
+pub(crate) fn load_intensity_profiles_from_disk(app_handle: &tauri::AppHandle) -> Result<HashMap<String, IntensityProfile>, CarbonLintError> {
+    let path = get_intensity_profiles_path(app_handle)?;
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = fs::read_to_string(&path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Callers hold the `DataDirLock` across the load and this save.
+pub(crate) fn save_intensity_profiles_to_disk(app_handle: &tauri::AppHandle, profiles: &HashMap<String, IntensityProfile>) -> Result<(), CarbonLintError> {
+    let path = get_intensity_profiles_path(app_handle)?;
+    if json_file_is_good(&path) {
+        backup_file(&path, "last-good")?;
+    }
+    let json = serde_json::to_string_pretty(profiles)?;
+    write_atomic(&path, json.as_bytes())
+}
+
+/// The static intensity table with any user-imported hourly profiles attached.
+/// Unreadable profiles are reported and the static table is used alone.
+pub(crate) fn load_intensity_map(app_handle: &tauri::AppHandle) -> HashMap<String, CarbonIntensityEntry> {
+    let mut map = get_carbon_intensity_map();
+    let profiles = load_intensity_profiles_from_disk(app_handle).unwrap_or_else(|e| {
+        if let Some(issues) = app_handle.try_state::<Arc<StorageIssues>>() {
+            issues.report("intensity_profiles", None, e.to_string());
+        }
+        HashMap::new()
+    });
+    for (code, profile) in profiles {
+        let entry = map.entry(code.clone()).or_insert_with(|| CarbonIntensityEntry {
+            region: code,
+            gco2_kwh: 475.0,
+            marginal_gco2_kwh: None,
+            hourly_profile: None,
+        });
+        entry.hourly_profile = Some(profile);
+    }
+    map
+}
+
+/// Looks up the intensity in effect at `timestamp_ms`, or `None` when the
+/// profile does not cover that instant.
+pub(crate) fn intensity_at(profile: &IntensityProfile, timestamp_ms: i64) -> Option<f64> {
+    match profile {
+        IntensityProfile::TypicalDay { hours, utc_offset_minutes } => {
+            let offset = FixedOffset::east_opt(utc_offset_minutes * 60)?;
+            let local = offset.timestamp_millis_opt(timestamp_ms).single()?;
+            let row = if hours.len() == 12 {
+                hours.get(local.month0() as usize)?
+            } else {
+                hours.first()?
+            };
+            row.get(local.hour() as usize).copied()
+        }
+        IntensityProfile::TimeSeries { points, interval_minutes } => {
+            let idx = points.partition_point(|p| p.timestamp.timestamp_millis() <= timestamp_ms);
+            if idx == 0 {
+                return None;
+            }
+            let point = &points[idx - 1];
+            let is_last = idx == points.len();
+            if is_last && timestamp_ms >= point.timestamp.timestamp_millis() + interval_minutes * 60_000 {
+                return None;
+            }
+            Some(point.gco2_kwh)
+        }
+    }
+}
+
+pub(crate) fn validate_intensity_profile(profile: &mut IntensityProfile) -> Result<(), String> {
+    match profile {
+        IntensityProfile::TypicalDay { hours, .. } => {
+            if hours.len() != 1 && hours.len() != 12 {
+                return Err("Typical-day profile needs 1 or 12 rows".to_string());
+            }
+            if hours.iter().any(|row| row.len() != 24) {
+                return Err("Each typical-day row needs 24 hourly values".to_string());
+            }
+        }
+        IntensityProfile::TimeSeries { points, interval_minutes } => {
+            if points.is_empty() {
+                return Err("Time series has no points".to_string());
+            }
+            if *interval_minutes <= 0 {
+                return Err("Time series interval must be positive".to_string());
+            }
+            points.sort_by_key(|p| p.timestamp);
+        }
+    }
+    Ok(())
+}
+
+// ============================================
+// Scheduling
+// ============================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SuggestRunWindowOptions {
+    /// Used to look up past runs when duration or energy are not given.
+    pub(crate) command: Option<String>,
+    pub(crate) project: Option<String>,
+    pub(crate) duration_minutes: Option<f64>,
+    pub(crate) energy_kwh: Option<f64>,
+    /// Latest acceptable finish time (RFC 3339).
+    pub(crate) deadline: String,
+    #[serde(default = "default_step_minutes")]
+    pub(crate) step_minutes: i64,
+}
+
+fn default_step_minutes() -> i64 { 15 }
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RunWindowSuggestion {
+    pub(crate) start: String,
+    pub(crate) end: String,
+    pub(crate) duration_minutes: f64,
+    pub(crate) energy_kwh: f64,
+    pub(crate) forecast_source: String,
+    pub(crate) expected_intensity: f64,
+    pub(crate) expected_carbon_grams: f64,
+    pub(crate) now_intensity: f64,
+    pub(crate) now_carbon_grams: f64,
+    pub(crate) savings_grams: f64,
+    pub(crate) savings_percent: f64,
+}
+
+/// Forecast for the configured region: the provider's if it has one,
+/// otherwise the region's imported hourly profile.
+pub(crate) fn load_intensity_forecast(app_handle: &tauri::AppHandle, settings: &AppSettings) -> Result<(String, IntensityProfile), CarbonLintError> {
+    let provider = intensity_provider_from_settings(&settings.intensity_provider);
+    let zone = settings.intensity_provider.zone.clone().unwrap_or_else(|| settings.region.clone());
+    if let Ok(points) = provider.forecast(&zone) {
+        if !points.is_empty() {
+            let profile = IntensityProfile::TimeSeries { points, interval_minutes: default_interval_minutes() };
+            return Ok((provider.name().to_string(), profile));
+        }
+    }
+    load_intensity_profiles_from_disk(app_handle)?
+        .remove(&settings.region)
+        .map(|profile| ("profile".to_string(), profile))
+        .ok_or_else(|| CarbonLintError::NotFound(format!("No intensity forecast available for {}", settings.region)))
+}
+
+/// Most start times `suggest_run_window` weighs, so a tiny step against a
+/// distant deadline is rejected rather than searched.
+const MAX_WINDOW_CANDIDATES: i64 = 10_000;
+
+/// Start times to consider: now, then every `step_ms` on step-aligned times
+/// while the run still finishes by `deadline_ms`.
+pub(crate) fn window_candidates(now_ms: i64, duration_ms: i64, deadline_ms: i64, step_ms: i64) -> Result<Vec<i64>, CarbonLintError> {
+    let invalid = |message: String| CarbonLintError::InvalidInput(message);
+    if duration_ms <= 0 || step_ms <= 0 {
+        return Err(invalid("Duration and step must be positive".to_string()));
+    }
+    if now_ms.checked_add(duration_ms).is_none_or(|end| end > deadline_ms) {
+        return Err(invalid("The run cannot finish before the deadline".to_string()));
+    }
+    // Aligned to the step so suggestions land on round times.
+    let first = (now_ms / step_ms + 1).checked_mul(step_ms);
+    let count = match first.and_then(|first| first.checked_add(duration_ms)) {
+        Some(end) if end <= deadline_ms => (deadline_ms - end) / step_ms + 1,
+        _ => 0,
+    };
+    let first = first.unwrap_or_default();
+    if count > MAX_WINDOW_CANDIDATES {
+        return Err(invalid(format!(
+            "The step gives {} start times before the deadline; use a larger step (at most {} start times)",
+            count, MAX_WINDOW_CANDIDATES
+        )));
+    }
+    Ok(std::iter::once(now_ms).chain((0..count).map(|i| first + i * step_ms)).collect())
+}
+
+/// The candidate start with the lowest mean intensity over the run, and that
+/// intensity; the earliest start wins ties.
+pub(crate) fn lowest_intensity_start(profile: &IntensityProfile, candidates: &[i64], duration_ms: i64, fallback: f64) -> Option<(i64, f64)> {
+    candidates
+        .iter()
+        .map(|&start| (start, mean_intensity(profile, start, duration_ms, fallback)))
+        .fold(None, |best, candidate| match best {
+            Some((_, lowest)) if lowest <= candidate.1 => best,
+            _ => Some(candidate),
+        })
+}
+
+/// Mean intensity over `[start_ms, start_ms + duration_ms)`, sampled every
+/// five minutes; instants the forecast does not cover use `fallback`.
+pub(crate) fn mean_intensity(profile: &IntensityProfile, start_ms: i64, duration_ms: i64, fallback: f64) -> f64 {
+    const STEP_MS: i64 = 5 * 60_000;
+    let mut total = 0.0;
+    let mut count = 0;
+    let mut t = start_ms;
+    while t < start_ms + duration_ms.max(1) {
+        total += intensity_at(profile, t).unwrap_or(fallback);
+        count += 1;
+        t += STEP_MS;
+    }
+    total / count as f64
+}
+
+/// Average duration (minutes) and energy (kWh) of past runs of `command`.
+pub(crate) fn past_run_averages(runs: &[RunResult], command: &str, project: Option<&str>) -> Option<(f64, f64)> {
+    let matching: Vec<&RunResult> = runs
+        .iter()
+        .filter(|r| r.command == command && project.is_none_or(|p| r.project == p))
+        .collect();
+    if matching.is_empty() {
+        return None;
+    }
+    let n = matching.len() as f64;
+    let minutes = matching.iter().map(|r| r.durationMs as f64 / 60_000.0).sum::<f64>() / n;
+    let energy = matching.iter().map(|r| r.energy.total_kwh).sum::<f64>() / n;
+    Some((minutes, energy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn ms(rfc3339: &str) -> i64 {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().timestamp_millis()
+    }
+
+    fn point(rfc3339: &str, gco2_kwh: f64) -> IntensityPoint {
+        IntensityPoint { timestamp: DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc), gco2_kwh }
+    }
+
+    fn typical_day(rows: usize, utc_offset_minutes: i32) -> IntensityProfile {
+        // Value = month * 100 + hour, so lookups are easy to check.
+        let hours = (0..rows).map(|m| (0..24).map(|h| (m * 100 + h) as f64).collect()).collect();
+        IntensityProfile::TypicalDay { hours, utc_offset_minutes }
+    }
+
+    #[test]
+    fn intensity_at_reads_single_row_by_local_hour() {
+        let profile = typical_day(1, 0);
+        assert_eq!(intensity_at(&profile, ms("2024-03-10T07:30:00Z")), Some(7.0));
+
+        let profile = typical_day(1, 120);
+        assert_eq!(intensity_at(&profile, ms("2024-03-10T07:30:00Z")), Some(9.0));
+    }
+
+    #[test]
+    fn intensity_at_picks_month_row_in_local_time() {
+        let profile = typical_day(12, -60);
+        // 00:30 UTC on 1 March is still 23:30 on 29 February locally.
+        assert_eq!(intensity_at(&profile, ms("2024-03-01T00:30:00Z")), Some(123.0));
+        assert_eq!(intensity_at(&profile, ms("2024-12-15T12:00:00Z")), Some(1111.0));
+    }
+
+    #[test]
+    fn intensity_at_holds_series_points_until_the_next() {
+        let profile = IntensityProfile::TimeSeries {
+            points: vec![point("2024-01-01T00:00:00Z", 100.0), point("2024-01-01T01:00:00Z", 200.0)],
+            interval_minutes: 30,
+        };
+        assert_eq!(intensity_at(&profile, ms("2023-12-31T23:59:59Z")), None);
+        assert_eq!(intensity_at(&profile, ms("2024-01-01T00:00:00Z")), Some(100.0));
+        assert_eq!(intensity_at(&profile, ms("2024-01-01T00:59:59Z")), Some(100.0));
+        assert_eq!(intensity_at(&profile, ms("2024-01-01T01:29:59Z")), Some(200.0));
+        assert_eq!(intensity_at(&profile, ms("2024-01-01T01:30:00Z")), None);
+    }
+
+    #[test]
+    fn validate_intensity_profile_checks_typical_day_shape() {
+        assert!(validate_intensity_profile(&mut typical_day(1, 0)).is_ok());
+        assert!(validate_intensity_profile(&mut typical_day(12, 0)).is_ok());
+        assert!(validate_intensity_profile(&mut typical_day(2, 0)).is_err());
+
+        let mut short_row = IntensityProfile::TypicalDay { hours: vec![vec![1.0; 23]], utc_offset_minutes: 0 };
+        assert!(validate_intensity_profile(&mut short_row).is_err());
+    }
+
+    #[test]
+    fn validate_intensity_profile_sorts_series_and_rejects_bad_ones() {
+        let mut series = IntensityProfile::TimeSeries {
+            points: vec![point("2024-01-01T02:00:00Z", 2.0), point("2024-01-01T01:00:00Z", 1.0)],
+            interval_minutes: 60,
+        };
+        validate_intensity_profile(&mut series).unwrap();
+        let IntensityProfile::TimeSeries { points, .. } = &series else { unreachable!() };
+        assert_eq!(points.iter().map(|p| p.gco2_kwh).collect::<Vec<_>>(), vec![1.0, 2.0]);
+
+        let mut empty = IntensityProfile::TimeSeries { points: Vec::new(), interval_minutes: 60 };
+        assert!(validate_intensity_profile(&mut empty).is_err());
+        let mut zero_interval = IntensityProfile::TimeSeries { points: vec![point("2024-01-01T00:00:00Z", 1.0)], interval_minutes: 0 };
+        assert!(validate_intensity_profile(&mut zero_interval).is_err());
+    }
+
+    fn series(points: &[(&str, f64)]) -> IntensityProfile {
+        IntensityProfile::TimeSeries {
+            points: points.iter().map(|(t, v)| point(t, *v)).collect(),
+            interval_minutes: 60,
+        }
+    }
+
+    #[test]
+    fn window_candidates_step_from_now_to_the_deadline() {
+        let now = ms("2024-05-01T10:07:00Z");
+        let hour = 3_600_000;
+        let candidates = window_candidates(now, hour, ms("2024-05-01T12:00:00Z"), 30 * 60_000).unwrap();
+        let expected: Vec<i64> = ["10:07", "10:30", "11:00"].iter().map(|t| ms(&format!("2024-05-01T{}:00Z", t))).collect();
+        assert_eq!(candidates, expected);
+
+        // Only now fits when the deadline leaves no room to wait.
+        assert_eq!(window_candidates(now, hour, now + hour, 60_000).unwrap(), vec![now]);
+    }
+
+    #[test]
+    fn window_candidates_reject_impossible_or_unbounded_searches() {
+        let now = ms("2024-05-01T10:00:00Z");
+        let too_short = window_candidates(now, 2 * 3_600_000, now + 3_600_000, 60_000);
+        assert!(matches!(too_short, Err(CarbonLintError::InvalidInput(m)) if m.contains("cannot finish")));
+        assert!(window_candidates(now, 0, now + 3_600_000, 60_000).is_err());
+        assert!(window_candidates(now, 60_000, now + 3_600_000, 0).is_err());
+
+        let year = 365 * 24 * 3_600_000;
+        let tiny_step = window_candidates(now, 60_000, now + year, 1);
+        assert!(matches!(tiny_step, Err(CarbonLintError::InvalidInput(m)) if m.contains("larger step")));
+        assert!(window_candidates(now, i64::MAX, i64::MAX, 60_000).is_err());
+        assert_eq!(window_candidates(now, 60_000, i64::MAX, i64::MAX).unwrap(), vec![now]);
+    }
+
+    #[test]
+    fn lowest_intensity_start_picks_the_cleanest_window() {
+        let forecast = series(&[
+            ("2024-05-01T10:00:00Z", 300.0),
+            ("2024-05-01T11:00:00Z", 100.0),
+            ("2024-05-01T12:00:00Z", 100.0),
+            ("2024-05-01T13:00:00Z", 400.0),
+        ]);
+        let candidates: Vec<i64> = (10..=12).map(|h| ms(&format!("2024-05-01T{}:00:00Z", h))).collect();
+        let best = lowest_intensity_start(&forecast, &candidates, 2 * 3_600_000, 500.0);
+        assert_eq!(best, Some((ms("2024-05-01T11:00:00Z"), 100.0)));
+
+        // Ties keep the earliest start.
+        let flat = series(&[("2024-05-01T10:00:00Z", 200.0)]);
+        let tied = lowest_intensity_start(&flat, &candidates[..1], 30 * 60_000, 200.0);
+        assert_eq!(tied, Some((candidates[0], 200.0)));
+        assert_eq!(lowest_intensity_start(&flat, &[], 60_000, 200.0), None);
+    }
+
+    #[test]
+    fn mean_intensity_uses_the_fallback_where_the_forecast_has_no_data() {
+        let start = ms("2024-05-01T10:00:00Z");
+        let hour = 3_600_000;
+        // Nothing before the first point or after the last point's interval.
+        let forecast = series(&[("2024-05-01T11:00:00Z", 100.0)]);
+        assert_eq!(mean_intensity(&forecast, start, hour, 400.0), 400.0);
+        assert_eq!(mean_intensity(&forecast, start + hour, hour, 400.0), 100.0);
+        assert_eq!(mean_intensity(&forecast, start + hour / 2, hour, 400.0), 250.0);
+        assert_eq!(mean_intensity(&forecast, start + 2 * hour, hour, 400.0), 400.0);
+
+        // Gaps between points hold the earlier value.
+        let gappy = series(&[("2024-05-01T10:00:00Z", 100.0), ("2024-05-01T14:00:00Z", 300.0)]);
+        assert_eq!(mean_intensity(&gappy, start + 2 * hour, hour, 400.0), 100.0);
+
+        let empty = series(&[]);
+        assert_eq!(mean_intensity(&empty, start, hour, 400.0), 400.0);
+    }
+
+    type Pairs = Vec<(String, String)>;
+
+    /// Serves one canned body (or error) and records each request.
+    struct FakeBackend {
+        response: Result<String, String>,
+        requests: RefCell<Vec<(String, Pairs, Pairs)>>,
+    }
+
+    impl HttpBackend for FakeBackend {
+        fn get(&self, url: &str, query: &[(&str, &str)], headers: &[(&str, &str)]) -> Result<String, String> {
+            let owned = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            self.requests.borrow_mut().push((url.to_string(), owned(query), owned(headers)));
+            self.response.clone()
+        }
+    }
+
+    fn provider(kind: IntensityProviderKind, response: Result<&str, &str>) -> HttpIntensityProvider<FakeBackend> {
+        HttpIntensityProvider {
+            backend: FakeBackend {
+                response: response.map(str::to_string).map_err(str::to_string),
+                requests: RefCell::new(Vec::new()),
+            },
+            kind,
+            base_url: "https://api.example/".to_string(),
+            token: Some("secret".to_string()),
+        }
+    }
+
+    fn reading(fetched_at: &str, gco2_kwh: f64) -> IntensityReading {
+        IntensityReading {
+            provider: "electricityMaps".to_string(),
+            zone: "DE".to_string(),
+            gco2_kwh,
+            timestamp: fetched_at.to_string(),
+            fetched_at: fetched_at.to_string(),
+            stale: false,
+        }
+    }
+
+    #[test]
+    fn electricity_maps_reads_latest_intensity_with_zone_as_query() {
+        let body = r#"{"zone":"US-CAL-CISO","carbonIntensity":302,"datetime":"2024-05-01T10:00:00.000Z"}"#;
+        let em = provider(IntensityProviderKind::ElectricityMaps, Ok(body));
+
+        let reading = em.current("US-CAL-CISO&x=1").unwrap();
+        assert_eq!((reading.provider.as_str(), reading.gco2_kwh), ("electricityMaps", 302.0));
+        assert_eq!(reading.timestamp, "2024-05-01T10:00:00.000Z");
+        assert!(!reading.stale);
+
+        let requests = em.backend.requests.borrow();
+        let (url, query, headers) = &requests[0];
+        assert_eq!(url, "https://api.example/v3/carbon-intensity/latest");
+        // Left to the backend to encode, rather than pasted into the URL.
+        assert_eq!(query, &vec![("zone".to_string(), "US-CAL-CISO&x=1".to_string())]);
+        assert!(headers.contains(&("auth-token".to_string(), "secret".to_string())));
+    }
+
+    #[test]
+    fn uk_carbon_intensity_prefers_actual_over_forecast() {
+        let actual = r#"{"data":[{"from":"2024-05-01T10:00Z","intensity":{"forecast":180,"actual":175}}]}"#;
+        let uk = provider(IntensityProviderKind::UkCarbonIntensity, Ok(actual));
+        let reading = uk.current("GB").unwrap();
+        assert_eq!((reading.provider.as_str(), reading.gco2_kwh), ("ukCarbonIntensity", 175.0));
+        assert_eq!(reading.timestamp, "2024-05-01T10:00Z");
+        let requests = uk.backend.requests.borrow();
+        assert_eq!(requests[0].0, "https://api.example/intensity");
+        assert!(requests[0].1.is_empty());
+
+        let forecast_only = r#"{"data":[{"from":"2024-05-01T10:00Z","intensity":{"forecast":180,"actual":null}}]}"#;
+        let uk = provider(IntensityProviderKind::UkCarbonIntensity, Ok(forecast_only));
+        assert_eq!(uk.current("GB").unwrap().gco2_kwh, 180.0);
+    }
+
+    #[test]
+    fn providers_report_missing_intensity_and_backend_errors() {
+        let em = provider(IntensityProviderKind::ElectricityMaps, Ok(r#"{"zone":"DE"}"#));
+        assert_eq!(em.current("DE").unwrap_err(), "electricityMaps response has no intensity");
+
+        let uk = provider(IntensityProviderKind::UkCarbonIntensity, Ok(r#"{"data":[]}"#));
+        assert_eq!(uk.current("GB").unwrap_err(), "ukCarbonIntensity response has no intensity");
+
+        let garbled = provider(IntensityProviderKind::ElectricityMaps, Ok("<html>"));
+        assert!(garbled.current("DE").is_err());
+
+        let down = provider(IntensityProviderKind::UkCarbonIntensity, Err("connection refused"));
+        assert_eq!(down.current("GB").unwrap_err(), "connection refused");
+        assert!(down.forecast("GB").is_err());
+    }
+
+    #[test]
+    fn forecasts_are_parsed_and_sorted() {
+        let body = r#"{"forecast":[
+            {"carbonIntensity":250,"datetime":"2024-05-01T11:00:00.000Z"},
+            {"carbonIntensity":300,"datetime":"2024-05-01T10:00:00.000Z"},
+            {"datetime":"2024-05-01T12:00:00.000Z"}
+        ]}"#;
+        let em = provider(IntensityProviderKind::ElectricityMaps, Ok(body));
+        let points = em.forecast("DE").unwrap();
+        let values: Vec<(i64, f64)> = points.iter().map(|p| (p.timestamp.timestamp_millis(), p.gco2_kwh)).collect();
+        assert_eq!(values, vec![(ms("2024-05-01T10:00:00Z"), 300.0), (ms("2024-05-01T11:00:00Z"), 250.0)]);
+        assert_eq!(em.backend.requests.borrow()[0].1, vec![("zone".to_string(), "DE".to_string())]);
+
+        let body = r#"{"data":[{"from":"2024-05-01T10:30Z","intensity":{"forecast":150}}]}"#;
+        let uk = provider(IntensityProviderKind::UkCarbonIntensity, Ok(body));
+        let points = uk.forecast("GB").unwrap();
+        assert_eq!(points[0].timestamp.timestamp_millis(), ms("2024-05-01T10:30:00Z"));
+        assert_eq!(points[0].gco2_kwh, 150.0);
+    }
+
+    #[test]
+    fn fresh_cached_readings_skip_the_provider() {
+        let em = provider(IntensityProviderKind::ElectricityMaps, Err("unreachable"));
+        let now = DateTime::parse_from_rfc3339("2024-05-01T10:20:00Z").unwrap().with_timezone(&Utc);
+        let cached = reading("2024-05-01T10:00:00Z", 200.0);
+
+        let result = cached_or_fetched(&em, "DE", Some(cached), 30, now);
+        assert!(matches!(result, Some(CachedOrFetched::Cached(r)) if r.gco2_kwh == 200.0 && !r.stale));
+        assert!(em.backend.requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn expired_cache_is_refreshed_or_used_stale() {
+        let now = DateTime::parse_from_rfc3339("2024-05-01T11:00:00Z").unwrap().with_timezone(&Utc);
+        let cached = reading("2024-05-01T10:00:00Z", 200.0);
+
+        let body = r#"{"carbonIntensity":320,"datetime":"2024-05-01T11:00:00.000Z"}"#;
+        let up = provider(IntensityProviderKind::ElectricityMaps, Ok(body));
+        let result = cached_or_fetched(&up, "DE", Some(cached.clone()), 30, now);
+        assert!(matches!(result, Some(CachedOrFetched::Fetched(r)) if r.gco2_kwh == 320.0));
+
+        let down = provider(IntensityProviderKind::ElectricityMaps, Err("timeout"));
+        let result = cached_or_fetched(&down, "DE", Some(cached), 30, now);
+        assert!(matches!(result, Some(CachedOrFetched::Cached(r)) if r.gco2_kwh == 200.0 && r.stale));
+        assert_eq!(down.backend.requests.borrow().len(), 1);
+
+        assert!(cached_or_fetched(&down, "DE", None, 30, now).is_none());
+    }
+
+    /// Serves one HTTP response after `delay` on a local port and returns
+    /// the base URL and a receiver for the request head.
+    fn mock_server(status: &str, body: &str, delay: Duration) -> (String, std::sync::mpsc::Receiver<String>) {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let Ok((mut stream, _)) = listener.accept() else {
+                return;
+            };
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line != "\r\n" {
+                head.push_str(&line);
+                line.clear();
+            }
+            let _ = sender.send(head);
+            thread::sleep(delay);
+            let _ = stream.write_all(response.as_bytes());
+        });
+        (base_url, receiver)
+    }
+
+    fn ureq_provider(base_url: String, timeout: Duration) -> HttpIntensityProvider<UreqBackend> {
+        HttpIntensityProvider {
+            backend: UreqBackend::with_timeout(timeout),
+            kind: IntensityProviderKind::ElectricityMaps,
+            base_url,
+            token: Some("secret".to_string()),
+        }
+    }
+
+    #[test]
+    fn ureq_backend_fetches_from_a_local_server() {
+        let body = r#"{"zone":"DE","carbonIntensity":321,"datetime":"2024-05-01T10:00:00.000Z"}"#;
+        let (base_url, requests) = mock_server("200 OK", body, Duration::ZERO);
+        let reading = ureq_provider(base_url, Duration::from_secs(5)).current("DE & AT").unwrap();
+        assert_eq!(reading.gco2_kwh, 321.0);
+
+        let head = requests.recv().unwrap();
+        assert!(head.starts_with("GET /v3/carbon-intensity/latest?zone=DE+%26+AT HTTP/1.1"), "{}", head);
+        assert!(head.to_ascii_lowercase().contains("auth-token: secret"));
+    }
+
+    #[test]
+    fn ureq_backend_reports_status_decode_and_timeout_errors() {
+        let (base_url, _requests) = mock_server("503 Service Unavailable", r#"{"error":"down"}"#, Duration::ZERO);
+        let error = ureq_provider(base_url, Duration::from_secs(5)).current("DE").unwrap_err();
+        assert!(error.contains("503"), "{}", error);
+
+        let (base_url, _requests) = mock_server("200 OK", "<html>maintenance</html>", Duration::ZERO);
+        assert!(ureq_provider(base_url, Duration::from_secs(5)).current("DE").is_err());
+
+        let (base_url, _requests) = mock_server("200 OK", r#"{"carbonIntensity":1}"#, Duration::from_secs(3));
+        let started = Instant::now();
+        assert!(ureq_provider(base_url, Duration::from_millis(200)).current("DE").is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,855 @@
+//! Ways other programs drive the app: the local control socket, shell
+//! hooks and `carbonlint://` links.
+
+use super::*;
+
+// ============================================
+// Local Socket
+// ============================================
+
+pub(crate) fn get_ipc_socket_path(app_handle: &tauri::AppHandle) -> Result<PathBuf, CarbonLintError> {
+    #[cfg(windows)]
+    {
+        let _ = app_handle;
+        Ok(PathBuf::from(format!(r"\\.\pipe\carbonlint-{}", current_user_sid()?)))
+    }
+    #[cfg(not(windows))]
+    {
+        app_data_file(app_handle, "carbonlint.sock")
+    }
+}
+
+/// The current user's SID (`S-1-5-21-...`), which names the control pipe and
+/// is the only account its DACL admits.
+#[cfg(windows)]
+fn current_user_sid() -> Result<String, CarbonLintError> {
+    use windows_sys::Win32::Foundation::{CloseHandle, LocalFree, HANDLE};
+    use windows_sys::Win32::Security::Authorization::ConvertSidToStringSidW;
+    use windows_sys::Win32::Security::{GetTokenInformation, TokenUser, TOKEN_QUERY, TOKEN_USER};
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+    // SAFETY: the token handle is closed before returning, the TOKEN_USER
+    // buffer is sized by the first GetTokenInformation call and u64-aligned,
+    // and the string from ConvertSidToStringSidW is freed with LocalFree.
+    unsafe {
+        let mut token: HANDLE = std::ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut len = 0;
+        GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut len);
+        let mut buffer = vec![0u64; (len as usize).div_ceil(8)];
+        let queried = GetTokenInformation(token, TokenUser, buffer.as_mut_ptr().cast(), len, &mut len);
+        let error = std::io::Error::last_os_error();
+        CloseHandle(token);
+        if queried == 0 {
+            return Err(error.into());
+        }
+        let user = &*buffer.as_ptr().cast::<TOKEN_USER>();
+        let mut sid = std::ptr::null_mut();
+        if ConvertSidToStringSidW(user.User.Sid, &mut sid) == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let text = widestring::U16CStr::from_ptr_str(sid).to_string_lossy();
+        LocalFree(sid.cast());
+        Ok(text)
+    }
+}
+
+/// Listens on the control socket: a Unix domain socket in the data
+/// directory, or a per-user named pipe on Windows. Each connection carries
+/// either HTTP requests, as sent by `curl --unix-socket`, or JSON-RPC
+/// messages one per line, as typed into `socat`.
+pub(crate) fn spawn_ipc_listener(app_handle: tauri::AppHandle) -> Result<(), CarbonLintError> {
+    use interprocess::local_socket::{prelude::*, GenericFilePath, ListenerOptions};
+
+    let path = get_ipc_socket_path(&app_handle)?;
+    if LocalSocketStream::connect(path.as_path().to_fs_name::<GenericFilePath>()?).is_ok() {
+        return Err(CarbonLintError::SessionState("Another CarbonLint instance is listening on the socket".to_string()));
+    }
+    // A socket left behind by a crash refuses connections but still blocks binding.
+    let options = ListenerOptions::new()
+        .name(path.as_path().to_fs_name::<GenericFilePath>()?)
+        .try_overwrite(true);
+    // Pipes can't be overwritten: on Windows interprocess creates the first
+    // instance with FILE_FLAG_FIRST_PIPE_INSTANCE, so this fails if another
+    // process already holds the name. The protected DACL admits only this user.
+    #[cfg(windows)]
+    let options = {
+        use interprocess::os::windows::{local_socket::ListenerOptionsExt, security_descriptor::SecurityDescriptor};
+        let sddl = widestring::U16CString::from_str(format!("D:P(A;;GA;;;{})", current_user_sid()?))
+            .map_err(|e| CarbonLintError::Internal(e.to_string()))?;
+        options.security_descriptor(SecurityDescriptor::deserialize(&sddl)?)
+    };
+    let listener = options.create_sync()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let app_handle = app_handle.clone();
+            thread::spawn(move || {
+                let _ = serve_ipc_connection(&app_handle, stream);
+            });
+        }
+    });
+    Ok(())
+}
+
+fn serve_ipc_connection(
+    app_handle: &tauri::AppHandle,
+    stream: interprocess::local_socket::Stream,
+) -> Result<(), CarbonLintError> {
+    use interprocess::local_socket::traits::Stream as _;
+    use std::io::BufRead;
+
+    stream.set_recv_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = std::io::BufReader::new(&stream);
+    let mut writer = &stream;
+
+    if reader.fill_buf()?.first() == Some(&b'{') {
+        // Line mode stays open until the client hangs up.
+        stream.set_recv_timeout(None)?;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            if let Some(response) = handle_rpc_message(&line, |method, params| dispatch_rpc(app_handle, method, params)) {
+                writeln!(writer, "{}", response)?;
+            }
+            line.clear();
+        }
+        return Ok(());
+    }
+
+    let (path, body) = read_http_request(&mut reader)?;
+    let (status, body) = if path == "/rpc" {
+        match handle_rpc_message(&body, |method, params| dispatch_rpc(app_handle, method, params)) {
+            Some(response) => ("200 OK", response.to_string()),
+            None => ("204 No Content", String::new()),
+        }
+    } else {
+        let outcome = match path.strip_prefix("/shell/") {
+            Some(event) => handle_shell_event(app_handle, event, parse_form(&body)),
+            None => Err(CarbonLintError::NotFound(format!("No endpoint {}", path))),
+        };
+        match outcome {
+            Ok(()) => ("204 No Content", String::new()),
+            Err(e @ CarbonLintError::NotFound(_)) => ("404 Not Found", serde_json::to_string(&e)?),
+            Err(e @ CarbonLintError::InvalidInput(_)) => ("400 Bad Request", serde_json::to_string(&e)?),
+            Err(e) => ("500 Internal Server Error", serde_json::to_string(&e)?),
+        }
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RpcSessionParams {
+    #[serde(default)]
+    session: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RpcPhaseParams {
+    #[serde(default)]
+    session: Option<String>,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct RpcRunParams {
+    id: String,
+}
+
+/// Handles one JSON-RPC 2.0 message, running the method through `dispatch`
+/// (`dispatch_rpc` outside tests). Notifications (no `id`) get no response.
+/// Application errors use code -32000 with the usual `{code, message}` error
+/// as `data`.
+fn handle_rpc_message(
+    text: &str,
+    dispatch: impl FnOnce(&str, serde_json::Value) -> Option<Result<serde_json::Value, CarbonLintError>>,
+) -> Option<serde_json::Value> {
+    let request: serde_json::Value = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => return Some(rpc_error(serde_json::Value::Null, -32700, format!("Parse error: {}", e), None)),
+    };
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(|m| m.as_str()) else {
+        return Some(rpc_error(id.unwrap_or_default(), -32600, "Invalid request".to_string(), None));
+    };
+    let params = match request.get("params") {
+        None | Some(serde_json::Value::Null) => serde_json::json!({}),
+        Some(params) => params.clone(),
+    };
+
+    let outcome = dispatch(method, params);
+    let id = id?;
+    Some(match outcome {
+        Some(Ok(result)) => serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Some(Err(e @ CarbonLintError::InvalidInput(_))) => rpc_error(id, -32602, e.message().to_string(), Some(&e)),
+        Some(Err(e)) => rpc_error(id, -32000, e.message().to_string(), Some(&e)),
+        None => rpc_error(id, -32601, format!("Method not found: {}", method), None),
+    })
+}
+
+fn rpc_error(id: serde_json::Value, code: i64, message: String, data: Option<&CarbonLintError>) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message, "data": data }
+    })
+}
+
+fn rpc_params<T: serde::de::DeserializeOwned>(params: serde_json::Value) -> Result<T, CarbonLintError> {
+    serde_json::from_value(params).map_err(|e| CarbonLintError::InvalidInput(format!("Invalid params: {}", e)))
+}
+
+/// Runs `method` through the same commands the webview uses; `None` if
+/// there is no such method.
+fn dispatch_rpc(
+    app_handle: &tauri::AppHandle,
+    method: &str,
+    params: serde_json::Value,
+) -> Option<Result<serde_json::Value, CarbonLintError>> {
+    let state = || app_handle.state::<AppState>();
+    let result = match method {
+        "start" => rpc_params(params)
+            .and_then(|options| start_profiling_impl(app_handle, &state(), options))
+            .map(|session| session_started(&session)),
+        "stop" => rpc_params::<RpcSessionParams>(params)
+            .and_then(|p| {
+                let name = p.session.unwrap_or_else(default_session_name);
+                stop_profiling_impl(app_handle, &state(), &name, StopReason::Manual)
+            })
+            .and_then(|run| Ok(serde_json::to_value(run)?)),
+        "status" => get_profiling_status(state()),
+        "pause" => rpc_params::<RpcSessionParams>(params).and_then(|p| pause_profiling(state(), p.session)),
+        "resume" => rpc_params::<RpcSessionParams>(params).and_then(|p| resume_profiling(state(), p.session)),
+        "markPhase" => rpc_params::<RpcPhaseParams>(params).and_then(|p| mark_phase(state(), p.session, p.name)),
+        "getRun" => rpc_params::<RpcRunParams>(params).and_then(|p| {
+            let run = get_run(app_handle.clone(), p.id.clone())?
+                .ok_or_else(|| CarbonLintError::NotFound(format!("No run {}", p.id)))?;
+            Ok(serde_json::to_value(run)?)
+        }),
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Reads one request and returns its path and body.
+fn read_http_request(reader: &mut impl std::io::BufRead) -> Result<(String, String), CarbonLintError> {
+    const MAX_BODY: usize = 64 * 1024;
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let path = line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| CarbonLintError::InvalidInput("Malformed request line".to_string()))?
+        .to_string();
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(CarbonLintError::InvalidInput("Request body too large".to_string()));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok((path, String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn parse_form(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match text.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// ============================================
+// Shell Integration
+// ============================================
+
+/// Queues a hook event on its shell's worker and returns straight away, so
+/// the shell never waits on starting or saving a session. Only malformed
+/// requests are refused here; later failures are shown as notifications.
+fn handle_shell_event(app_handle: &tauri::AppHandle, event: &str, fields: HashMap<String, String>) -> Result<(), CarbonLintError> {
+    let pid: u32 = fields
+        .get("pid")
+        .and_then(|pid| pid.parse().ok())
+        .ok_or_else(|| CarbonLintError::InvalidInput("Missing or invalid shell pid".to_string()))?;
+    if !matches!(event, "preexec" | "precmd") {
+        return Err(CarbonLintError::NotFound(format!("Unknown shell event '{}'", event)));
+    }
+    let app = app_handle.clone();
+    let event = event.to_string();
+    let queues = app_handle.state::<AppState>().shell_queues.clone();
+    queues.enqueue(
+        pid,
+        Box::new(move || {
+            if let Err(e) = run_shell_event(&app, pid, &event, &fields) {
+                notify(&app, format!("Shell {} {}: {}", pid, event, e.message()));
+            }
+        }),
+    )
+}
+
+/// `preexec` opens a session scoped to the shell process; `precmd` saves it
+/// as a run, or drops it if the command was shorter than the configured
+/// minimum. Each shell has at most one session, named after its PID.
+fn run_shell_event(app_handle: &tauri::AppHandle, pid: u32, event: &str, fields: &HashMap<String, String>) -> Result<(), CarbonLintError> {
+    let name = format!("shell-{}", pid);
+    let state = app_handle.state::<AppState>();
+    let existing = state.profiling_sessions.lock()?.get(&name).cloned();
+
+    match event {
+        "preexec" => {
+            // A missed precmd (e.g. the hook was sourced mid-command) leaves a stale session.
+            if existing.is_some() {
+                discard_session_impl(app_handle, &state, &name)?;
+            }
+            let command = fields.get("command").map(|c| c.trim()).filter(|c| !c.is_empty());
+            let options = StartProfilingOptions {
+                session: Some(name),
+                stop_conditions: StopConditions { pid: Some(pid), ..Default::default() },
+                command: command.map(str::to_string),
+                cwd: fields.get("cwd").cloned(),
+                trigger: Some(RunTrigger::Shell),
+                ..Default::default()
+            };
+            start_profiling_impl(app_handle, &state, options).map(|_| ())
+        }
+        "precmd" => {
+            let Some(session) = existing else {
+                return Ok(());
+            };
+            let now = Utc::now().timestamp_millis();
+            let active_ms = now - session.start_time
+                - paused_ms_between(&session_excluded(&session, now), session.start_time, now);
+            let min_seconds = current_settings(app_handle)?.shell_integration.min_duration_seconds;
+            if (active_ms as f64) < min_seconds * 1000.0 {
+                discard_session_impl(app_handle, &state, &name)
+            } else {
+                stop_profiling_impl(app_handle, &state, &name, StopReason::CommandFinished).map(|_| ())
+            }
+        }
+        _ => Err(CarbonLintError::NotFound(format!("Unknown shell event '{}'", event))),
+    }
+}
+
+type ShellJob = Box<dyn FnOnce() + Send>;
+
+/// One worker thread per shell, running that shell's events in the order
+/// they arrived while other shells proceed in parallel. A worker exits once
+/// its queue has been empty for `idle`; the next event starts a new one.
+pub(crate) struct ShellQueues {
+    senders: Mutex<HashMap<u32, mpsc::Sender<ShellJob>>>,
+    idle: Duration,
+}
+
+impl Default for ShellQueues {
+    fn default() -> Self {
+        ShellQueues { senders: Mutex::default(), idle: Duration::from_secs(60) }
+    }
+}
+
+impl ShellQueues {
+    /// Queues `job` behind any earlier ones from the same shell.
+    fn enqueue(self: &Arc<Self>, pid: u32, job: ShellJob) -> Result<(), CarbonLintError> {
+        let mut senders = self.senders.lock()?;
+        // A worker that has gone (e.g. a job panicked) hands the job back.
+        let job = match senders.get(&pid) {
+            Some(sender) => match sender.send(job) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::SendError(job)) => job,
+            },
+            None => job,
+        };
+        let (sender, receiver) = mpsc::channel();
+        sender.send(job).map_err(|_| CarbonLintError::Internal("Shell queue closed".to_string()))?;
+        senders.insert(pid, sender);
+        let queues = self.clone();
+        thread::spawn(move || queues.work(pid, receiver));
+        Ok(())
+    }
+
+    fn work(&self, pid: u32, receiver: mpsc::Receiver<ShellJob>) {
+        loop {
+            let job = match receiver.recv_timeout(self.idle) {
+                Ok(job) => job,
+                Err(_) => {
+                    // Jobs are sent under the lock, so none can slip in
+                    // between this check and the removal.
+                    let Ok(mut senders) = self.senders.lock() else {
+                        return;
+                    };
+                    match receiver.try_recv() {
+                        Ok(job) => job,
+                        Err(_) => {
+                            senders.remove(&pid);
+                            return;
+                        }
+                    }
+                }
+            };
+            job();
+        }
+    }
+}
+
+/// Quotes `text` as a single word for the given shell.
+pub(crate) fn shell_quote(shell: &str, text: &str) -> String {
+    match shell {
+        "fish" => format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'")),
+        _ => format!("'{}'", text.replace('\'', "'\\''")),
+    }
+}
+
+pub(crate) const SHELL_HOOK_BASH: &str = r#"# CarbonLint shell integration for bash. Source this from ~/.bashrc.
+# Uses bash-preexec's hook arrays if it is loaded first; otherwise runs ahead
+# of any existing DEBUG trap and PROMPT_COMMAND.
+__carbonlint_send() {
+    curl -s -m 2 --unix-socket __SOCKET__ "http://localhost/shell/$1" \
+        --data-urlencode "pid=$$" --data-urlencode "cwd=$PWD" --data-urlencode "command=$2" \
+        >/dev/null 2>&1
+}
+__carbonlint_begin() {
+    __carbonlint_running=1
+    __carbonlint_send preexec "$1"
+}
+__carbonlint_preexec() {
+    [ -n "$__carbonlint_armed" ] || return 0
+    [ "$BASH_COMMAND" = __carbonlint_precmd ] && return 0
+    __carbonlint_armed=
+    local line
+    line=$(HISTTIMEFORMAT= builtin history 1)
+    [[ $line =~ ^[[:space:]]*[0-9]+[*]?[[:space:]]+(.*)$ ]] && line=${BASH_REMATCH[1]} || line=$BASH_COMMAND
+    __carbonlint_begin "$line"
+}
+__carbonlint_precmd() {
+    local status=$?
+    # Disarm so the rest of PROMPT_COMMAND is not taken for a command.
+    __carbonlint_armed=
+    if [ -n "$__carbonlint_running" ]; then
+        __carbonlint_running=
+        __carbonlint_send precmd ""
+    fi
+    return $status
+}
+__carbonlint_arm() {
+    local status=$?
+    __carbonlint_armed=1
+    return $status
+}
+__carbonlint_return() {
+    return "$1"
+}
+# Sourced files and functions don't see the DEBUG trap, so the first prompt
+# passes in `trap -p DEBUG` (`trap -- '<command>' DEBUG`) and the existing
+# trap runs after ours.
+__carbonlint_install() {
+    local status=$? previous=$1
+    if [[ $previous != *__carbonlint_preexec* ]]; then
+        previous=${previous#trap -- }
+        eval "__carbonlint_previous_debug=${previous% DEBUG}"
+        trap '__carbonlint_status=$?; __carbonlint_preexec; __carbonlint_return $__carbonlint_status; eval "$__carbonlint_previous_debug"' DEBUG
+    fi
+    PROMPT_COMMAND=${PROMPT_COMMAND/'__carbonlint_install "$(trap -p DEBUG)"'/__carbonlint_arm}
+    __carbonlint_armed=1
+    return $status
+}
+if [ -n "${bash_preexec_imported:-}${__bp_imported:-}" ]; then
+    [[ " ${preexec_functions[*]} " == *" __carbonlint_begin "* ]] || preexec_functions+=(__carbonlint_begin)
+    [[ " ${precmd_functions[*]} " == *" __carbonlint_precmd "* ]] || precmd_functions+=(__carbonlint_precmd)
+elif [[ $PROMPT_COMMAND != *__carbonlint_precmd* ]]; then
+    PROMPT_COMMAND=$'__carbonlint_precmd\n'"${PROMPT_COMMAND:+$PROMPT_COMMAND$'\n'}"'__carbonlint_install "$(trap -p DEBUG)"'
+fi
+"#;
+
+pub(crate) const SHELL_HOOK_ZSH: &str = r#"# CarbonLint shell integration for zsh. Source this from ~/.zshrc.
+__carbonlint_send() {
+    curl -s -m 2 --unix-socket __SOCKET__ "http://localhost/shell/$1" \
+        --data-urlencode "pid=$$" --data-urlencode "cwd=$PWD" --data-urlencode "command=$2" \
+        >/dev/null 2>&1
+}
+__carbonlint_preexec() {
+    __carbonlint_running=1
+    __carbonlint_send preexec "$1"
+}
+__carbonlint_precmd() {
+    [[ -n $__carbonlint_running ]] || return 0
+    __carbonlint_running=
+    __carbonlint_send precmd ""
+}
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __carbonlint_preexec
+add-zsh-hook precmd __carbonlint_precmd
+"#;
+
+pub(crate) const SHELL_HOOK_FISH: &str = r#"# CarbonLint shell integration for fish. Source this from ~/.config/fish/config.fish.
+function __carbonlint_send
+    curl -s -m 2 --unix-socket __SOCKET__ "http://localhost/shell/$argv[1]" \
+        --data-urlencode "pid=$fish_pid" --data-urlencode "cwd=$PWD" --data-urlencode "command=$argv[2]" \
+        >/dev/null 2>&1
+end
+function __carbonlint_preexec --on-event fish_preexec
+    __carbonlint_send preexec "$argv"
+end
+function __carbonlint_postexec --on-event fish_postexec
+    __carbonlint_send precmd ""
+end
+"#;
+
+// ============================================
+// Deep Links
+// ============================================
+
+pub(crate) enum DeepLinkAction {
+    Start(Box<StartProfilingOptions>),
+    Stop(Option<String>),
+    Run(String),
+}
+
+/// Parses `carbonlint://start?project=&command=&branch=&commit=&session=&maxDurationMinutes=`,
+/// `carbonlint://stop?session=` and `carbonlint://run/<id>`. Any web page can
+/// open these links, so only labels are accepted: nothing that names a
+/// directory to inspect or a process to watch.
+fn parse_deep_link(url: &tauri::Url) -> Result<DeepLinkAction, CarbonLintError> {
+    const MAX_VALUE_LEN: usize = 256;
+    let invalid = |message: String| CarbonLintError::InvalidInput(message);
+    if url.scheme() != "carbonlint" {
+        return Err(invalid(format!("Not a carbonlint:// link: {}", url)));
+    }
+    let action = url.host_str().unwrap_or_default();
+    let segments: Vec<&str> = url.path_segments().map(|s| s.filter(|s| !s.is_empty()).collect()).unwrap_or_default();
+
+    let allowed: &[&str] = match action {
+        "start" => &["session", "project", "command", "branch", "commit", "maxDurationMinutes"],
+        "stop" => &["session"],
+        _ => &[],
+    };
+    let mut params = HashMap::new();
+    for (key, value) in url.query_pairs() {
+        if !allowed.contains(&key.as_ref()) {
+            return Err(invalid(format!("Unknown parameter '{}' for carbonlint://{}", key, action)));
+        }
+        if value.trim().is_empty() || value.len() > MAX_VALUE_LEN {
+            return Err(invalid(format!("Invalid value for '{}'", key)));
+        }
+        if params.insert(key.into_owned(), value.into_owned()).is_some() {
+            return Err(invalid("Parameters may only be given once".to_string()));
+        }
+    }
+
+    match (action, segments.as_slice()) {
+        ("start", []) => {
+            let max_duration_minutes = params
+                .remove("maxDurationMinutes")
+                .map(|value| {
+                    value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|minutes| minutes.is_finite() && *minutes > 0.0)
+                        .ok_or_else(|| invalid(format!("Invalid maxDurationMinutes '{}'", value)))
+                })
+                .transpose()?;
+            Ok(DeepLinkAction::Start(Box::new(StartProfilingOptions {
+                session: params.remove("session"),
+                stop_conditions: StopConditions { max_duration_minutes, ..Default::default() },
+                command: params.remove("command"),
+                project: params.remove("project"),
+                branch: params.remove("branch"),
+                commit: params.remove("commit"),
+                ..Default::default()
+            })))
+        }
+        ("stop", []) => Ok(DeepLinkAction::Stop(params.remove("session"))),
+        ("run", [id]) if id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+            Ok(DeepLinkAction::Run(id.to_string()))
+        }
+        _ => Err(invalid(format!("Unsupported link {}", url))),
+    }
+}
+
+/// A start or stop link waiting for the user to allow it in the dashboard.
+pub(crate) struct PendingDeepLink {
+    url: String,
+    pub(crate) prompt: String,
+    action: DeepLinkAction,
+}
+
+impl PendingDeepLink {
+    pub(crate) fn run(self, app_handle: &tauri::AppHandle) {
+        run_deep_link(app_handle, &self.url, self.action);
+    }
+}
+
+/// Handles a deep link on its own thread. Any web page can open these
+/// links, so starts and stops wait for the user to allow them in the
+/// dashboard (`confirm_deep_link`); a newer link replaces one still waiting.
+/// Run links open straight away.
+pub(crate) fn handle_deep_link(app_handle: &tauri::AppHandle, url: &tauri::Url) {
+    let app_handle = app_handle.clone();
+    let url = url.clone();
+    thread::spawn(move || match parse_deep_link(&url) {
+        Ok(action @ DeepLinkAction::Run(_)) => run_deep_link(&app_handle, url.as_str(), action),
+        Ok(action) => {
+            let pending = PendingDeepLink { url: url.to_string(), prompt: deep_link_prompt(&action), action };
+            if let Ok(mut slot) = app_handle.state::<AppState>().deep_link_pending.lock() {
+                *slot = Some(pending);
+            }
+            show_main_window(&app_handle);
+        }
+        Err(e) => notify(&app_handle, format!("Could not open {}: {}", url, e.message())),
+    });
+}
+
+fn deep_link_prompt(action: &DeepLinkAction) -> String {
+    match action {
+        DeepLinkAction::Start(options) => {
+            let mut prompt = format!("Start profiling session '{}'", options.session.as_deref().unwrap_or(DEFAULT_SESSION));
+            if let Some(project) = &options.project {
+                prompt.push_str(&format!(" for {}", project));
+            }
+            if let Some(command) = &options.command {
+                prompt.push_str(&format!(" ({})", command));
+            }
+            prompt + "?"
+        }
+        DeepLinkAction::Stop(session) => {
+            format!("Stop profiling session '{}' and save the run?", session.as_deref().unwrap_or(DEFAULT_SESSION))
+        }
+        DeepLinkAction::Run(id) => format!("Open run {}?", id),
+    }
+}
+
+/// Carries out a deep link through the same session logic as the commands,
+/// then brings the dashboard forward on the run it concerns.
+fn run_deep_link(app_handle: &tauri::AppHandle, url: &str, action: DeepLinkAction) {
+    let state = app_handle.state::<AppState>();
+    let outcome = match action {
+        DeepLinkAction::Start(options) => start_profiling_impl(app_handle, &state, *options).map(|session| {
+            notify(app_handle, format!("Profiling session '{}' started.", session.name));
+            None
+        }),
+        DeepLinkAction::Stop(session) => {
+            let name = session.as_deref().unwrap_or(DEFAULT_SESSION);
+            stop_profiling_impl(app_handle, &state, name, StopReason::Manual).map(|run| Some(run.id))
+        }
+        DeepLinkAction::Run(id) => app_handle.state::<RunStore>().get(&id).and_then(|run| match run {
+            Some(run) => Ok(Some(run.id)),
+            None => Err(CarbonLintError::NotFound(format!("No run {}", id))),
+        }),
+    };
+    match outcome {
+        Ok(run) => {
+            if let Some(id) = run {
+                if let Ok(mut pending) = state.deep_link_run.lock() {
+                    *pending = Some(id);
+                }
+            }
+            show_main_window(app_handle);
+        }
+        Err(e) => notify(app_handle, format!("Could not open {}: {}", url, e.message())),
+    }
+}
+
+/// Shows and focuses the dashboard, and tells it to check for a run to open
+/// or a link to confirm.
+pub(crate) fn show_main_window(app_handle: &tauri::AppHandle) {
+    use tauri::Emitter;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+        let _ = window.emit("deep-link", ());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo_dispatch(method: &str, params: serde_json::Value) -> Option<Result<serde_json::Value, CarbonLintError>> {
+        match method {
+            "echo" => Some(Ok(params)),
+            "fail" => Some(Err(CarbonLintError::NotFound("No run x".into()))),
+            "bad" => Some(Err(CarbonLintError::InvalidInput("Invalid params".into()))),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn handle_rpc_message_wraps_results_and_defaults_params() {
+        let response = handle_rpc_message(r#"{"jsonrpc":"2.0","id":7,"method":"echo","params":{"a":1}}"#, echo_dispatch).unwrap();
+        assert_eq!(response, serde_json::json!({ "jsonrpc": "2.0", "id": 7, "result": { "a": 1 } }));
+
+        let response = handle_rpc_message(r#"{"jsonrpc":"2.0","id":"x","method":"echo","params":null}"#, echo_dispatch).unwrap();
+        assert_eq!(response["result"], serde_json::json!({}));
+        assert_eq!(response["id"], "x");
+    }
+
+    #[test]
+    fn handle_rpc_message_maps_errors_to_json_rpc_codes() {
+        let code = |text: &str| handle_rpc_message(text, echo_dispatch).unwrap()["error"]["code"].as_i64();
+
+        assert_eq!(code("{not json"), Some(-32700));
+        assert_eq!(code(r#"{"jsonrpc":"2.0","id":1}"#), Some(-32600));
+        assert_eq!(code(r#"{"jsonrpc":"2.0","id":1,"method":"nope"}"#), Some(-32601));
+        assert_eq!(code(r#"{"jsonrpc":"2.0","id":1,"method":"bad"}"#), Some(-32602));
+        assert_eq!(code(r#"{"jsonrpc":"2.0","id":1,"method":"fail"}"#), Some(-32000));
+
+        let response = handle_rpc_message(r#"{"jsonrpc":"2.0","id":1,"method":"fail"}"#, echo_dispatch).unwrap();
+        assert_eq!(response["error"]["message"], "No run x");
+        assert_eq!(response["error"]["data"]["code"], "not_found");
+    }
+
+    #[test]
+    fn handle_rpc_message_runs_notifications_without_responding() {
+        let mut ran = false;
+        let response = handle_rpc_message(r#"{"jsonrpc":"2.0","method":"fail"}"#, |method, params| {
+            ran = true;
+            echo_dispatch(method, params)
+        });
+        assert!(ran);
+        assert_eq!(response, None);
+    }
+
+    fn link(text: &str) -> Result<DeepLinkAction, CarbonLintError> {
+        parse_deep_link(&tauri::Url::parse(text).unwrap())
+    }
+
+    #[test]
+    fn parse_deep_link_reads_start_stop_and_run_links() {
+        let Ok(DeepLinkAction::Start(options)) = link("carbonlint://start?project=api&command=cargo%20bench&maxDurationMinutes=1.5") else {
+            panic!("expected a start link");
+        };
+        assert_eq!(options.project.as_deref(), Some("api"));
+        assert_eq!(options.command.as_deref(), Some("cargo bench"));
+        assert_eq!(options.session, None);
+        assert_eq!(options.stop_conditions.max_duration_minutes, Some(1.5));
+        assert_eq!(deep_link_prompt(&DeepLinkAction::Start(options)), "Start profiling session 'default' for api (cargo bench)?");
+
+        let Ok(DeepLinkAction::Stop(session)) = link("carbonlint://stop?session=bench") else {
+            panic!("expected a stop link");
+        };
+        assert_eq!(session.as_deref(), Some("bench"));
+        assert!(matches!(link("carbonlint://stop"), Ok(DeepLinkAction::Stop(None))));
+        assert!(matches!(link("carbonlint://run/run-42_a"), Ok(DeepLinkAction::Run(id)) if id == "run-42_a"));
+    }
+
+    #[test]
+    fn parse_deep_link_rejects_unknown_or_unsafe_input() {
+        let rejected = [
+            "https://start?project=api",
+            "carbonlint://start?cwd=/etc",
+            "carbonlint://start?pid=1",
+            "carbonlint://stop?project=api",
+            "carbonlint://start?project=",
+            "carbonlint://start?project=a&project=b",
+            "carbonlint://start?maxDurationMinutes=0",
+            "carbonlint://start?maxDurationMinutes=NaN",
+            "carbonlint://start/extra",
+            "carbonlint://run",
+            "carbonlint://run/a/b",
+            "carbonlint://run/..%2Fsettings",
+            "carbonlint://delete/run-1",
+        ];
+        for text in rejected {
+            assert!(matches!(link(text), Err(CarbonLintError::InvalidInput(_))), "{} was accepted", text);
+        }
+        let long = format!("carbonlint://start?command={}", "x".repeat(257));
+        assert!(link(&long).is_err());
+    }
+
+    #[test]
+    fn percent_decode_handles_escapes_plus_and_malformed_input() {
+        assert_eq!(percent_decode("cargo+build%20--release"), "cargo build --release");
+        assert_eq!(percent_decode("%2Fhome%2fme"), "/home/me");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn parse_form_decodes_pairs_and_tolerates_missing_values() {
+        let form = parse_form("pid=42&cwd=%2Ftmp%2Fa+b&command=&flag&&command2=x%3Dy");
+        assert_eq!(form.get("pid").map(String::as_str), Some("42"));
+        assert_eq!(form.get("cwd").map(String::as_str), Some("/tmp/a b"));
+        assert_eq!(form.get("command").map(String::as_str), Some(""));
+        assert_eq!(form.get("flag").map(String::as_str), Some(""));
+        assert_eq!(form.get("command2").map(String::as_str), Some("x=y"));
+        assert_eq!(form.len(), 5);
+        assert!(parse_form("").is_empty());
+    }
+
+    #[test]
+    fn shell_events_run_in_order_per_shell_without_blocking_the_sender() {
+        let queues = Arc::new(ShellQueues { idle: Duration::from_millis(50), ..Default::default() });
+        let (done, finished) = mpsc::channel();
+        let queued_at = Instant::now();
+        for (pid, step) in [(1, 1), (2, 1), (1, 2), (2, 2), (1, 3)] {
+            let done = done.clone();
+            let job = move || {
+                thread::sleep(Duration::from_millis(100));
+                done.send((pid, step)).unwrap();
+            };
+            queues.enqueue(pid, Box::new(job)).unwrap();
+        }
+        assert!(queued_at.elapsed() < Duration::from_millis(100));
+
+        let order: Vec<(u32, u32)> = (0..5).map(|_| finished.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        let steps = |pid| order.iter().filter(|e| e.0 == pid).map(|e| e.1).collect::<Vec<_>>();
+        assert_eq!((steps(1), steps(2)), (vec![1, 2, 3], vec![1, 2]));
+        // The two shells' jobs overlapped rather than queueing behind each other.
+        assert!(queued_at.elapsed() < Duration::from_millis(450));
+
+        // Idle workers exit, and the next event starts a fresh one.
+        thread::sleep(Duration::from_millis(200));
+        assert!(queues.senders.lock().unwrap().is_empty());
+        queues.enqueue(1, Box::new(move || done.send((1, 4)).unwrap())).unwrap();
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)).unwrap(), (1, 4));
+    }
+}
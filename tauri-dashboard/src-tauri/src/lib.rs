@@ -1,13 +1,113 @@
-use sysinfo::{System, Networks, ProcessRefreshKind};
+use sysinfo::{Pid, System, Networks, ProcessRefreshKind};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
 use tauri::{State, Manager};
-use chrono::{Utc}; 
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDate, TimeZone, Timelike, Utc};
 use std::thread;
 use std::time::{Duration, Instant};
 use std::fs;
-use std::path::PathBuf;
-use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+
+mod git;
+mod intensity;
+mod ipc;
+mod sessions;
+mod storage;
+
+use git::*;
+use intensity::*;
+use ipc::*;
+use sessions::*;
+use storage::*;
+
+// ============================================
+// Errors
+// ============================================
+
+/// Errors returned by commands. The frontend receives them as
+/// `{ "code": "storage_io", "message": "..." }`.
+#[derive(Debug)]
+enum CarbonLintError {
+    /// Reading or writing the app data directory failed.
+    StorageIo(String),
+    /// Stored data exists but could not be parsed.
+    CorruptData(String),
+    /// No session to stop, or one already running.
+    SessionState(String),
+    InvalidSettings(String),
+    InvalidInput(String),
+    NotFound(String),
+    /// Unexpected internal failure, such as a poisoned lock.
+    Internal(String),
+}
+
+impl CarbonLintError {
+    fn code(&self) -> &'static str {
+        match self {
+            CarbonLintError::StorageIo(_) => "storage_io",
+            CarbonLintError::CorruptData(_) => "corrupt_data",
+            CarbonLintError::SessionState(_) => "session_state",
+            CarbonLintError::InvalidSettings(_) => "invalid_settings",
+            CarbonLintError::InvalidInput(_) => "invalid_input",
+            CarbonLintError::NotFound(_) => "not_found",
+            CarbonLintError::Internal(_) => "internal",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            CarbonLintError::StorageIo(m)
+            | CarbonLintError::CorruptData(m)
+            | CarbonLintError::SessionState(m)
+            | CarbonLintError::InvalidSettings(m)
+            | CarbonLintError::InvalidInput(m)
+            | CarbonLintError::NotFound(m)
+            | CarbonLintError::Internal(m) => m,
+        }
+    }
+}
+
+impl std::fmt::Display for CarbonLintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for CarbonLintError {}
+
+impl Serialize for CarbonLintError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("CarbonLintError", 2)?;
+        s.serialize_field("code", self.code())?;
+        s.serialize_field("message", self.message())?;
+        s.end()
+    }
+}
+
+impl From<std::io::Error> for CarbonLintError {
+    fn from(e: std::io::Error) -> Self { CarbonLintError::StorageIo(e.to_string()) }
+}
+
+impl From<rusqlite::Error> for CarbonLintError {
+    fn from(e: rusqlite::Error) -> Self { CarbonLintError::StorageIo(e.to_string()) }
+}
+
+impl From<serde_json::Error> for CarbonLintError {
+    fn from(e: serde_json::Error) -> Self { CarbonLintError::CorruptData(e.to_string()) }
+}
+
+/// Mostly a blocking task that panicked or was cancelled; path lookups map
+/// to `StorageIo` where they happen.
+impl From<tauri::Error> for CarbonLintError {
+    fn from(e: tauri::Error) -> Self { CarbonLintError::Internal(e.to_string()) }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for CarbonLintError {
+    fn from(e: std::sync::PoisonError<T>) -> Self { CarbonLintError::Internal(e.to_string()) }
+}
 
 // ============================================
 // Data Structures
@@ -64,13 +164,189 @@ struct SystemStats {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct ProfilingSession {
     id: String,
+    /// Key among active sessions; see `DEFAULT_SESSION`.
+    #[serde(default = "default_session_name")]
+    name: String,
     start_time: i64,
     start_timestamp: String,
     command: String,
     project: String,    
     branch: String,
     commit: String,
-    samples: Vec<ResourceSample>,
+    #[serde(deserialize_with = "deserialize_sample_buffer")]
+    samples: SampleBuffer,
+    /// Set while paused; no samples are recorded until resumed.
+    #[serde(default)]
+    paused_at: Option<i64>,
+    #[serde(default)]
+    pauses: Vec<PauseInterval>,
+    #[serde(default)]
+    phases: Vec<PhaseMarker>,
+    #[serde(default)]
+    stop_conditions: StopConditions,
+    /// Suspends and forward clock jumps, excluded like pauses.
+    #[serde(default)]
+    gaps: Vec<TimeGap>,
+    #[serde(default)]
+    trigger: Option<RunTrigger>,
+    #[serde(default)]
+    repository: Option<RunRepository>,
+    /// Set while a stop is saving the run, so a second stop, a discard or
+    /// an automatic stop can't race it.
+    #[serde(skip)]
+    stopping: bool,
+    /// Set when an automatic stop failed, so it is reported once rather than
+    /// retried on every sample. A manual stop can still retry it.
+    #[serde(skip)]
+    stop_failed: bool,
+}
+
+/// The git checkout a session was started in, beyond its branch and commit.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RunRepository {
+    root: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_url: Option<String>,
+    /// Uncommitted changes to tracked files; unknown without a `git` binary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dirty: Option<bool>,
+}
+
+/// What opened a session that the user did not start themselves.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum RunTrigger {
+    ProcessWatch,
+    Shell,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum GapKind {
+    Suspend,
+    ClockJump,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct TimeGap {
+    start: i64,
+    end: i64,
+    kind: GapKind,
+}
+
+/// Limits after which a session stops itself. All are optional.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct StopConditions {
+    max_duration_minutes: Option<f64>,
+    max_energy_kwh: Option<f64>,
+    max_carbon_grams: Option<f64>,
+    /// Stop when this process exits.
+    pid: Option<u32>,
+    /// Stop once CPU use has stayed below `idle_cpu_percent` this long.
+    idle_minutes: Option<f64>,
+    #[serde(default = "default_idle_cpu_percent")]
+    idle_cpu_percent: f32,
+}
+
+fn default_idle_cpu_percent() -> f32 { 5.0 }
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum StopReason {
+    Manual,
+    MaxDuration,
+    EnergyLimit,
+    CarbonLimit,
+    ProcessExited,
+    Idle,
+    CommandFinished,
+}
+
+impl StopReason {
+    fn describe(self) -> &'static str {
+        match self {
+            StopReason::Manual => "stopped manually",
+            StopReason::MaxDuration => "maximum duration reached",
+            StopReason::EnergyLimit => "energy limit reached",
+            StopReason::CarbonLimit => "carbon limit reached",
+            StopReason::ProcessExited => "target process exited",
+            StopReason::Idle => "system idle",
+            StopReason::CommandFinished => "command finished",
+        }
+    }
+}
+
+/// A session's samples with bounded memory use. Once `cap` entries are held,
+/// the older half is merged pairwise, so older data loses resolution
+/// gradually while recent data stays exact. Whole-session totals are
+/// accumulated as samples arrive, so averages and peaks never depend on the
+/// merged entries.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct SampleBuffer {
+    entries: Vec<BufferedSample>,
+    totals: SampleTotals,
+    cap: usize,
+}
+
+/// One recorded sample, or the average of several merged ones stamped with
+/// their count-weighted mean time.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct BufferedSample {
+    #[serde(flatten)]
+    sample: ResourceSample,
+    cpu_min: f32,
+    cpu_max: f32,
+    memory_max: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct SampleTotals {
+    count: usize,
+    cpu_sum: f64,
+    memory_percent_sum: f64,
+    disk_read_sum: f64,
+    disk_write_sum: f64,
+    net_rx_sum: f64,
+    net_tx_sum: f64,
+    memory_max: u64,
+}
+
+/// Checkpoints written before the buffer existed hold a plain sample list.
+fn deserialize_sample_buffer<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<SampleBuffer, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Buffer(SampleBuffer),
+        Legacy(Vec<ResourceSample>),
+    }
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::Buffer(buffer) => buffer,
+        Stored::Legacy(samples) => {
+            let mut buffer = SampleBuffer::with_cap(default_session_sample_cap());
+            for sample in samples {
+                buffer.push(sample);
+            }
+            buffer
+        }
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PauseInterval {
+    start: i64,
+    end: i64,
+}
+
+/// Start of a named phase; it runs until the next marker or the session end.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PhaseMarker {
+    name: String,
+    timestamp: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -83,14 +359,33 @@ struct ResourceSample {
     disk_write_per_sec: f64,
     net_rx_per_sec: f64,
     net_tx_per_sec: f64,
+    /// Raw samples this one averages; more than 1 once merged or downsampled.
+    #[serde(default = "one_sample")]
+    count: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+fn one_sample() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 struct StartProfilingOptions {
+    /// Session name; defaults to `DEFAULT_SESSION`.
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default, rename = "stopConditions")]
+    stop_conditions: StopConditions,
     command: Option<String>,
     project: Option<String>,
     branch: Option<String>,
     commit: Option<String>,
+    /// Directory to read project, branch and commit from when they are not
+    /// given; defaults to the `projectDirectory` setting.
+    #[serde(default)]
+    cwd: Option<String>,
+    /// Set by the backend for automatic sessions, never by the webview.
+    #[serde(skip)]
+    trigger: Option<RunTrigger>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -99,8 +394,51 @@ struct CarbonResult {
     region: String,
     intensity: f64,
     pue: f64,
+    /// True when `intensity` was averaged from an hourly profile rather
+    /// than the region's constant.
+    #[serde(default)]
+    time_varying: bool,
+    /// Which intensity provider supplied the base figure, and when it was valid.
+    #[serde(default = "default_provider_name")]
+    intensity_provider: String,
+    #[serde(default)]
+    intensity_timestamp: String,
+    /// Which factor `intensity` and `total_grams` use; both are kept below.
+    #[serde(default)]
+    emission_factor: EmissionFactor,
+    #[serde(default)]
+    average_intensity: f64,
+    #[serde(default)]
+    average_grams: f64,
+    #[serde(default)]
+    marginal_intensity: f64,
+    #[serde(default)]
+    marginal_grams: f64,
+    /// GHG Protocol Scope 2 dual reporting. Location-based uses the grid
+    /// average; market-based uses the supplier's contractual factor. Both
+    /// exclude energy met by on-site generation.
+    #[serde(default)]
+    onsite_kwh: f64,
+    #[serde(default)]
+    location_grams: f64,
+    #[serde(default)]
+    market_intensity: f64,
+    #[serde(default)]
+    market_grams: f64,
 }
 
+/// Average factors describe the grid mix; marginal factors describe the
+/// plant that responds to extra load, and suit load-shifting decisions.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+enum EmissionFactor {
+    #[default]
+    Average,
+    Marginal,
+}
+
+fn default_provider_name() -> String { "static".to_string() }
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct EnergyResult {
     total_kwh: f64,
@@ -139,6 +477,8 @@ struct RunMetrics {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(non_snake_case)]
 struct RunResult {
+    #[serde(default)]
+    schemaVersion: u32,
     id: String,
     project: String,
     command: String,
@@ -151,12 +491,137 @@ struct RunResult {
     energy: EnergyResult,
     sampleCount: usize,
     durationMs: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cost: Option<CostResult>,
+    #[serde(default)]
+    water: WaterResult,
+    #[serde(default)]
+    hardwareProfile: String,
+    /// Earlier figures, oldest first, kept whenever the run is recomputed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    revisions: Vec<RunRevision>,
+    /// Set on runs recovered after a crash: the figures only cover the
+    /// session up to this checkpoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    truncatedAt: Option<String>,
+    /// Time spent paused; `durationMs` and all totals exclude it.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pausedMs: i64,
+    /// When the session was paused, so recomputing can leave those
+    /// stretches out of time-of-day weighting.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pauses: Vec<RunPause>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    phases: Vec<PhaseResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stopReason: Option<StopReason>,
+    /// Time the machine was suspended or the clock jumped; excluded from
+    /// `durationMs` and all totals, like pauses.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    gaps: Vec<RunGap>,
+    /// Set on runs opened automatically rather than by the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trigger: Option<RunTrigger>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repository: Option<RunRepository>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RunGap {
+    start: String,
+    end: String,
+    duration_ms: i64,
+    kind: GapKind,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RunPause {
+    start: String,
+    end: String,
+}
+
+fn is_zero(value: &i64) -> bool {
+    *value == 0
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct PhaseResult {
+    name: String,
+    start: String,
+    end: String,
+    /// Active time in the phase, excluding pauses.
+    duration_ms: i64,
+    sample_count: usize,
+    energy_kwh: f64,
+    carbon_grams: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RunRevision {
+    revised_at: String,
+    hardware_profile: String,
+    energy: EnergyResult,
+    carbon: CarbonResult,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cost: Option<CostResult>,
+    #[serde(default)]
+    water: WaterResult,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct WaterResult {
+    total_liters: f64,
+    /// Cooling water at the site, from WUE.
+    onsite_liters: f64,
+    /// Water consumed generating the grid electricity.
+    offsite_liters: f64,
+    wue: f64,
+    offsite_liters_per_kwh: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CostResult {
+    currency: String,
+    /// Energy-weighted average price over the run.
+    price_per_kwh: f64,
+    /// Grid energy billed, after PUE and on-site generation.
+    billed_kwh: f64,
+    total_cost: f64,
+    time_of_use: bool,
 }
 
 struct AppState {
     sys: Arc<Mutex<System>>,
     slow_metrics: Arc<Mutex<SlowMetrics>>,
-    profiling_session: Mutex<Option<ProfilingSession>>,
+    /// Active sessions keyed by name.
+    profiling_sessions: Mutex<HashMap<String, ProfilingSession>>,
+    /// Run a deep link asked to show, until the webview picks it up.
+    deep_link_run: Mutex<Option<String>>,
+    /// Start or stop link waiting for the user to allow it.
+    deep_link_pending: Mutex<Option<PendingDeepLink>>,
+    /// Per-shell workers for shell hook events.
+    shell_queues: Arc<ShellQueues>,
+}
+
+/// The session used by the tray, the global shortcut and any command called
+/// without a session name.
+const DEFAULT_SESSION: &str = "default";
+
+fn default_session_name() -> String {
+    DEFAULT_SESSION.to_string()
+}
+
+fn no_session(name: &str) -> CarbonLintError {
+    CarbonLintError::SessionState(format!("No profiling session '{}' in progress", name))
+}
+
+fn session_stopping(name: &str) -> CarbonLintError {
+    CarbonLintError::SessionState(format!("Profiling session '{}' is already being stopped", name))
 }
 
 #[derive(Clone, Default)]
@@ -172,6 +637,8 @@ struct SlowMetrics {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct AppSettings {
+    #[serde(rename = "schemaVersion", default)]
+    schema_version: u32,
     region: String,
     pue: f64,
     #[serde(rename = "hardwareProfile")]
@@ -190,6 +657,166 @@ struct AppSettings {
     daily_carbon_budget: f64,
     #[serde(rename = "weeklyCarbonBudget", default = "default_weekly_budget")]
     weekly_carbon_budget: f64,
+    #[serde(rename = "intensityProvider", default)]
+    intensity_provider: IntensityProviderSettings,
+    #[serde(rename = "emissionFactor", default)]
+    emission_factor: EmissionFactor,
+    /// Contractual factor from a green tariff or PPA (gCO2/kWh); market-based
+    /// figures fall back to the grid average when unset.
+    #[serde(rename = "supplierEmissionFactor", default)]
+    supplier_emission_factor: Option<f64>,
+    #[serde(rename = "onsiteGeneration", default)]
+    onsite_generation: Option<OnsiteGeneration>,
+    #[serde(default)]
+    tariff: Option<Tariff>,
+    /// Water Usage Effectiveness of the site, litres per kWh of IT energy.
+    #[serde(default)]
+    wue: f64,
+    /// Water consumed per kWh of grid electricity generated, in litres.
+    #[serde(rename = "offsiteWaterIntensity", default)]
+    offsite_water_intensity: f64,
+    #[serde(rename = "energyModel", default)]
+    energy_model: EnergyModel,
+    #[serde(rename = "sampleRetention", default)]
+    sample_retention: SampleRetention,
+    #[serde(rename = "processWatch", default)]
+    process_watch: ProcessWatchSettings,
+    #[serde(rename = "shellIntegration", default)]
+    shell_integration: ShellIntegrationSettings,
+    /// Working directory for sessions started without one, such as from the
+    /// tray or global shortcut.
+    #[serde(rename = "projectDirectory", default)]
+    project_directory: Option<String>,
+}
+
+/// Runs recorded for commands typed in a hooked shell. Their figures are
+/// system-wide, like any session's, not the command's alone.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ShellIntegrationSettings {
+    /// Commands finishing sooner than this are not recorded.
+    min_duration_seconds: f64,
+}
+
+impl Default for ShellIntegrationSettings {
+    fn default() -> Self {
+        ShellIntegrationSettings { min_duration_seconds: 5.0 }
+    }
+}
+
+/// Processes that get a session of their own whenever they start. Like any
+/// session, a run's figures are system-wide: they cover everything the
+/// machine did while the process ran, not the process alone.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ProcessWatchSettings {
+    enabled: bool,
+    rules: Vec<WatchRule>,
+}
+
+impl Default for ProcessWatchSettings {
+    fn default() -> Self {
+        let rules = ["cargo", "rustc", "npm", "gradle", "docker build"]
+            .iter()
+            .map(|pattern| WatchRule { pattern: pattern.to_string(), project: None, command: None })
+            .collect();
+        ProcessWatchSettings { enabled: false, rules }
+    }
+}
+
+/// `pattern` is a process name, optionally followed by the arguments the
+/// command line must start with (`docker build`). `project` and `command`
+/// label the resulting runs; by default they come from the process's working
+/// directory and command line.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct WatchRule {
+    pattern: String,
+    #[serde(default)]
+    project: Option<String>,
+    #[serde(default)]
+    command: Option<String>,
+}
+
+impl WatchRule {
+    fn matches(&self, process: &sysinfo::Process) -> bool {
+        self.matches_command(process.name(), process.cmd().get(1..).unwrap_or_default())
+    }
+
+    /// `args` are the arguments after the program itself.
+    fn matches_command(&self, name: &str, args: &[String]) -> bool {
+        let mut words = self.pattern.split_whitespace();
+        let Some(program) = words.next() else {
+            return false;
+        };
+        let name = name.strip_suffix(".exe").unwrap_or(name);
+        let required: Vec<&str> = words.collect();
+        program.eq_ignore_ascii_case(name)
+            && args.len() >= required.len()
+            && required.iter().zip(args).all(|(word, arg)| *word == arg.as_str())
+    }
+}
+
+/// Samples stay at full resolution for `full_resolution_days`, then are
+/// downsampled to `downsampled_points` per run.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SampleRetention {
+    full_resolution_days: i64,
+    downsampled_points: usize,
+    /// Samples an active session keeps in memory before merging older ones.
+    #[serde(default = "default_session_sample_cap")]
+    session_sample_cap: usize,
+}
+
+/// Just under six hours at full resolution with the 2-second sampler.
+fn default_session_sample_cap() -> usize { 10_000 }
+
+/// Compaction merges the older half pairwise, which needs a few entries to
+/// leave room for new samples.
+const MIN_SESSION_SAMPLE_CAP: usize = 4;
+
+impl Default for SampleRetention {
+    fn default() -> Self {
+        SampleRetention {
+            full_resolution_days: 30,
+            downsampled_points: 300,
+            session_sample_cap: default_session_sample_cap(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+enum EnergyModel {
+    /// CPU power proportional to utilization, zero when idle.
+    #[default]
+    Linear,
+    /// CPU power rises linearly from an idle floor of 30% of TDP.
+    IdleFloor,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Tariff {
+    currency: String,
+    price_per_kwh: f64,
+    /// Optional time-of-use prices per hour of a typical day: 12 rows (one
+    /// per month) or a single row, local time. Overrides `price_per_kwh`.
+    #[serde(default)]
+    time_of_use: Option<Vec<Vec<f64>>>,
+    #[serde(default)]
+    utc_offset_minutes: i32,
+}
+
+/// Share (0-1) of consumption met by on-site generation for each hour of a
+/// typical day: 12 rows (one per month) or a single row, local time.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct OnsiteGeneration {
+    hourly_share: Vec<Vec<f64>>,
+    #[serde(default)]
+    utc_offset_minutes: i32,
 }
 
 fn default_daily_budget() -> f64 { 50.0 }
@@ -198,6 +825,7 @@ fn default_weekly_budget() -> f64 { 250.0 }
 impl Default for AppSettings {
     fn default() -> Self {
         AppSettings {
+            schema_version: SETTINGS_SCHEMA_VERSION,
             region: "GLOBAL-AVG".to_string(),
             pue: 1.0,
             hardware_profile: "laptop".to_string(),
@@ -208,6 +836,58 @@ impl Default for AppSettings {
             auto_start: false,
             daily_carbon_budget: 50.0,
             weekly_carbon_budget: 250.0,
+            intensity_provider: IntensityProviderSettings::default(),
+            emission_factor: EmissionFactor::Average,
+            supplier_emission_factor: None,
+            onsite_generation: None,
+            tariff: None,
+            wue: 0.0,
+            offsite_water_intensity: 0.0,
+            energy_model: EnergyModel::Linear,
+            sample_retention: SampleRetention::default(),
+            process_watch: ProcessWatchSettings::default(),
+            shell_integration: ShellIntegrationSettings::default(),
+            project_directory: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+enum IntensityProviderKind {
+    #[default]
+    Static,
+    /// Electricity Maps–compatible `/v3/carbon-intensity/latest?zone=` API.
+    ElectricityMaps,
+    /// UK Carbon Intensity–compatible `/intensity` API.
+    UkCarbonIntensity,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct IntensityProviderSettings {
+    kind: IntensityProviderKind,
+    #[serde(default)]
+    base_url: String,
+    #[serde(default)]
+    token: Option<String>,
+    /// Provider zone to query; defaults to the settings region code.
+    #[serde(default)]
+    zone: Option<String>,
+    #[serde(default = "default_cache_ttl_minutes")]
+    cache_ttl_minutes: i64,
+}
+
+fn default_cache_ttl_minutes() -> i64 { 30 }
+
+impl Default for IntensityProviderSettings {
+    fn default() -> Self {
+        IntensityProviderSettings {
+            kind: IntensityProviderKind::Static,
+            base_url: String::new(),
+            token: None,
+            zone: None,
+            cache_ttl_minutes: default_cache_ttl_minutes(),
         }
     }
 }
@@ -216,6 +896,41 @@ impl Default for AppSettings {
 struct CarbonIntensityEntry {
     region: String,
     gco2_kwh: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    marginal_gco2_kwh: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hourly_profile: Option<IntensityProfile>,
+}
+
+/// Time-varying grid intensity for a region. When present it replaces the
+/// constant `gco2_kwh` for any instant it covers.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum IntensityProfile {
+    /// A typical day per month: 12 rows (or a single row for all months) of
+    /// 24 hourly values, in the grid's local time.
+    #[serde(rename_all = "camelCase")]
+    TypicalDay {
+        hours: Vec<Vec<f64>>,
+        #[serde(default)]
+        utc_offset_minutes: i32,
+    },
+    /// An imported series; each point holds until the next one, the last
+    /// one for `interval_minutes`.
+    #[serde(rename_all = "camelCase")]
+    TimeSeries {
+        points: Vec<IntensityPoint>,
+        #[serde(default = "default_interval_minutes")]
+        interval_minutes: i64,
+    },
+}
+
+fn default_interval_minutes() -> i64 { 60 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct IntensityPoint {
+    timestamp: DateTime<Utc>,
+    gco2_kwh: f64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -224,15 +939,17 @@ struct HardwareProfileEntry {
     memory_watts_per_gb: f64,
 }
 
+// Marginal factors are rough estimates for the fossil plant typically on
+// the margin in each grid.
 fn get_carbon_intensity_map() -> HashMap<String, CarbonIntensityEntry> {
     let mut m = HashMap::new();
-    m.insert("US-WEST".into(), CarbonIntensityEntry { region: "California".into(), gco2_kwh: 210.0 });
-    m.insert("US-EAST".into(), CarbonIntensityEntry { region: "Virginia".into(), gco2_kwh: 380.0 });
-    m.insert("EU-WEST".into(), CarbonIntensityEntry { region: "Ireland".into(), gco2_kwh: 300.0 });
-    m.insert("EU-NORTH".into(), CarbonIntensityEntry { region: "Sweden".into(), gco2_kwh: 25.0 });
-    m.insert("ASIA-EAST".into(), CarbonIntensityEntry { region: "Japan".into(), gco2_kwh: 470.0 });
-    m.insert("ASIA-SOUTH".into(), CarbonIntensityEntry { region: "India".into(), gco2_kwh: 700.0 });
-    m.insert("GLOBAL-AVG".into(), CarbonIntensityEntry { region: "Mixed".into(), gco2_kwh: 475.0 });
+    m.insert("US-WEST".into(), CarbonIntensityEntry { region: "California".into(), gco2_kwh: 210.0, marginal_gco2_kwh: Some(430.0), hourly_profile: None });
+    m.insert("US-EAST".into(), CarbonIntensityEntry { region: "Virginia".into(), gco2_kwh: 380.0, marginal_gco2_kwh: Some(620.0), hourly_profile: None });
+    m.insert("EU-WEST".into(), CarbonIntensityEntry { region: "Ireland".into(), gco2_kwh: 300.0, marginal_gco2_kwh: Some(450.0), hourly_profile: None });
+    m.insert("EU-NORTH".into(), CarbonIntensityEntry { region: "Sweden".into(), gco2_kwh: 25.0, marginal_gco2_kwh: Some(350.0), hourly_profile: None });
+    m.insert("ASIA-EAST".into(), CarbonIntensityEntry { region: "Japan".into(), gco2_kwh: 470.0, marginal_gco2_kwh: Some(650.0), hourly_profile: None });
+    m.insert("ASIA-SOUTH".into(), CarbonIntensityEntry { region: "India".into(), gco2_kwh: 700.0, marginal_gco2_kwh: Some(900.0), hourly_profile: None });
+    m.insert("GLOBAL-AVG".into(), CarbonIntensityEntry { region: "Mixed".into(), gco2_kwh: 475.0, marginal_gco2_kwh: Some(700.0), hourly_profile: None });
     m
 }
 
@@ -248,50 +965,7 @@ fn get_hardware_profiles_map() -> HashMap<String, HardwareProfileEntry> {
 // Logic
 // ============================================
 
-fn get_runs_path(app_handle: &tauri::AppHandle) -> PathBuf {
-    let path = app_handle.path().app_data_dir().expect("failed to get app data dir");
-    fs::create_dir_all(&path).unwrap();
-    path.join("runs.json")
-}
-
-fn get_settings_path(app_handle: &tauri::AppHandle) -> PathBuf {
-    let path = app_handle.path().app_data_dir().expect("failed to get app data dir");
-    fs::create_dir_all(&path).unwrap();
-    path.join("settings.json")
-}
-
-fn load_settings_from_disk(app_handle: &tauri::AppHandle) -> AppSettings {
-    let path = get_settings_path(app_handle);
-    if path.exists() {
-        let content = fs::read_to_string(&path).unwrap_or_else(|_| "{}".to_string());
-        serde_json::from_str(&content).unwrap_or_default()
-    } else {
-        AppSettings::default()
-    }
-}
-
-fn save_settings_to_disk(app_handle: &tauri::AppHandle, settings: &AppSettings) {
-    let path = get_settings_path(app_handle);
-    let json = serde_json::to_string_pretty(settings).unwrap();
-    fs::write(path, json).unwrap();
-}
-
-fn save_run_to_disk(app_handle: &tauri::AppHandle, run: &RunResult) {
-    let path = get_runs_path(app_handle);
-    let mut runs: Vec<RunResult> = if path.exists() {
-        let content = fs::read_to_string(&path).unwrap_or_else(|_| "[]".to_string());
-        serde_json::from_str(&content).unwrap_or_default()
-    } else {
-        Vec::new()
-    };
-    
-    runs.push(run.clone());
-    
-    let json = serde_json::to_string_pretty(&runs).unwrap();
-    fs::write(path, json).unwrap();
-}
-
-fn calculate_energy(metrics: &RunMetrics, duration_seconds: f64, hw_profile: &str) -> EnergyResult {
+fn calculate_energy(metrics: &RunMetrics, duration_seconds: f64, hw_profile: &str, model: EnergyModel) -> EnergyResult {
     let profiles = get_hardware_profiles_map();
     let profile = profiles.get(hw_profile).cloned()
         .unwrap_or(HardwareProfileEntry { cpu_tdp_watts: 15.0, memory_watts_per_gb: 0.3 });
@@ -302,7 +976,13 @@ fn calculate_energy(metrics: &RunMetrics, duration_seconds: f64, hw_profile: &st
 
     let hours = duration_seconds / 3600.0;
 
-    let cpu_energy = (profile.cpu_tdp_watts * (metrics.cpuUtilization as f64 / 100.0) * hours) / 1000.0;
+    let cpu_load = metrics.cpuUtilization as f64 / 100.0;
+    let cpu_share = match model {
+        EnergyModel::Linear => cpu_load,
+        // An idle CPU still draws a fair fraction of TDP; scale only the rest.
+        EnergyModel::IdleFloor => 0.3 + 0.7 * cpu_load,
+    };
+    let cpu_energy = (profile.cpu_tdp_watts * cpu_share * hours) / 1000.0;
     let gpu_energy = if metrics.gpuUtilization > 0.0 {
         (gpu_tdp * (metrics.gpuUtilization as f64 / 100.0) * hours) / 1000.0
     } else {
@@ -331,6 +1011,9 @@ fn calculate_carbon(energy_kwh: f64, region: &str, pue: f64) -> CarbonResult {
     let intensity = intensity_map.get(region)
         .map(|e| e.gco2_kwh)
         .unwrap_or(475.0);
+    let marginal_intensity = intensity_map.get(region)
+        .and_then(|e| e.marginal_gco2_kwh)
+        .unwrap_or(intensity);
     let region_name = intensity_map.get(region)
         .map(|e| e.region.clone())
         .unwrap_or_else(|| region.to_string());
@@ -343,517 +1026,1294 @@ fn calculate_carbon(energy_kwh: f64, region: &str, pue: f64) -> CarbonResult {
         region: region_name,
         intensity,
         pue,
+        time_varying: false,
+        intensity_provider: default_provider_name(),
+        intensity_timestamp: String::new(),
+        emission_factor: EmissionFactor::Average,
+        average_intensity: intensity,
+        average_grams: carbon_grams,
+        marginal_intensity,
+        marginal_grams: effective_energy * marginal_intensity,
+        onsite_kwh: 0.0,
+        location_grams: carbon_grams,
+        market_intensity: intensity,
+        market_grams: carbon_grams,
     }
 }
 
-// ============================================
-// Commands
-// ============================================
-
-#[tauri::command]
-fn get_current_stats(state: State<AppState>) -> SystemStats {
-    let mut sys = state.sys.lock().unwrap();
-    sys.refresh_cpu();
-    sys.refresh_memory();
-
-    let cpu_usage = sys.global_cpu_info().cpu_usage();
-    let total_mem = sys.total_memory() / 1024 / 1024;
-    let used_mem = sys.used_memory() / 1024 / 1024;
-    let free_mem = sys.free_memory() / 1024 / 1024;
-    let mem_percent = if total_mem > 0 {
-        (used_mem as f32 / total_mem as f32) * 100.0
-    } else {
-        0.0
+/// Recomputes both figures from their intensities and points `intensity`
+/// and `total_grams` at the chosen factor.
+fn apply_emission_factor(result: &mut CarbonResult, energy_kwh: f64, factor: EmissionFactor) {
+    let effective_energy = energy_kwh * result.pue - result.onsite_kwh;
+    result.average_grams = effective_energy * result.average_intensity;
+    result.marginal_grams = effective_energy * result.marginal_intensity;
+    result.emission_factor = factor;
+    (result.intensity, result.total_grams) = match factor {
+        EmissionFactor::Average => (result.average_intensity, result.average_grams),
+        EmissionFactor::Marginal => (result.marginal_intensity, result.marginal_grams),
     };
+}
 
-    let slow = state.slow_metrics.lock().unwrap().clone();
-
-    // Store sample if profiling
-    let mut session_guard = state.profiling_session.lock().unwrap();
-    if let Some(session) = session_guard.as_mut() {
-        session.samples.push(ResourceSample {
-            timestamp: Utc::now().timestamp_millis(),
-            cpu_utilization: cpu_usage,
-            memory_used: used_mem,
-            memory_percent: mem_percent,
-            disk_read_per_sec: slow.disk.read_per_sec,
-            disk_write_per_sec: slow.disk.write_per_sec,
-            net_rx_per_sec: slow.network.rx_per_sec,
-            net_tx_per_sec: slow.network.tx_per_sec,
-        });
+/// Like `calculate_carbon`, but charges each sample interval at the
+/// intensity in effect when it ran. Falls back to the region constant for
+/// intervals the profile does not cover.
+fn calculate_carbon_timed(
+    energy: &EnergyResult,
+    samples: &[ResourceSample],
+    active: &[(i64, i64)],
+    entry: Option<&CarbonIntensityEntry>,
+    settings: &AppSettings,
+) -> CarbonResult {
+    let mut result = calculate_carbon(energy.total_kwh, &settings.region, settings.pue);
+    if let Some(entry) = entry {
+        result.average_intensity = entry.gco2_kwh;
+        result.marginal_intensity = entry.marginal_gco2_kwh.unwrap_or(entry.gco2_kwh);
+        if let Some(profile) = &entry.hourly_profile {
+            if let Some(intensity) = profile_weighted_intensity(profile, entry.gco2_kwh, samples, active, settings) {
+                result.average_intensity = intensity;
+                result.time_varying = true;
+            }
+        }
     }
-
-    SystemStats {
-        timestamp: Utc::now().to_rfc3339(),
-        cpu: CpuStats {
-            utilization: cpu_usage,
-            cores: sys.cpus().len(),
-        },
-        memory: MemoryStats {
-            total_mb: total_mem,
-            used_mb: used_mem,
-            free_mb: free_mem,
-            usage_percent: mem_percent,
-            total: sys.total_memory(),
-        },
-        disk: slow.disk,
-        network: slow.network,
-        gpu: slow.gpu,
-        uptime: System::uptime(),
+    if let Some(generation) = &settings.onsite_generation {
+        let share_profile = IntensityProfile::TypicalDay {
+            hours: generation.hourly_share.clone(),
+            utc_offset_minutes: generation.utc_offset_minutes,
+        };
+        let share = profile_weighted_intensity(&share_profile, 0.0, samples, active, settings)
+            .unwrap_or(0.0)
+            .clamp(0.0, 1.0);
+        result.onsite_kwh = energy.total_kwh * settings.pue * share;
     }
+    apply_emission_factor(&mut result, energy.total_kwh, settings.emission_factor);
+
+    let grid_kwh = energy.total_kwh * settings.pue - result.onsite_kwh;
+    result.location_grams = result.average_grams;
+    result.market_intensity = settings.supplier_emission_factor.unwrap_or(result.average_intensity);
+    result.market_grams = grid_kwh * result.market_intensity;
+    result
 }
 
-#[tauri::command]
-fn start_profiling(
-    options: StartProfilingOptions,
-    state: State<AppState>,
-) -> Result<serde_json::Value, String> {
-    let mut session_guard = state.profiling_session.lock().map_err(|e| e.to_string())?;
-    
-    if session_guard.is_some() {
-        return Err("Profiling session already in progress".to_string());
-    }
-
-    let now = Utc::now();
-    let session = ProfilingSession {
-        id: format!("cl_{}", now.timestamp_millis()),
-        start_time: now.timestamp_millis(),
-        start_timestamp: now.to_rfc3339(),
-        command: options.command.unwrap_or_else(|| "manual profiling".to_string()),
-        project: options.project.unwrap_or_else(|| "CarbonLint Dashboard".to_string()),
-        branch: options.branch.unwrap_or_else(|| "main".to_string()),
-        commit: options.commit.unwrap_or_else(|| "N/A".to_string()),
-        samples: Vec::new(),
+/// Average intensity over the run with each sample interval weighted by
+/// the energy it drew, so busy stretches count for more than idle ones.
+/// Only the parts of each interval inside `active` count, so pauses and
+/// suspends don't pull the average towards the hours they covered.
+fn profile_weighted_intensity(
+    profile: &IntensityProfile,
+    fallback: f64,
+    samples: &[ResourceSample],
+    active: &[(i64, i64)],
+    settings: &AppSettings,
+) -> Option<f64> {
+    let (Some(&(start_ms, _)), Some(&(_, end_ms))) = (active.first(), active.last()) else {
+        return None;
     };
+    let mut weighted = 0.0;
+    let mut total_weight = 0.0;
+    let mut prev_ms = start_ms;
+    for (i, sample) in samples.iter().enumerate() {
+        let interval_end = if i + 1 == samples.len() { end_ms } else { sample.timestamp };
+        let interval_start = std::mem::replace(&mut prev_ms, interval_end);
+        let metrics = metrics_from_samples(std::slice::from_ref(sample));
+        for &(from, to) in active {
+            let (from, to) = (from.max(interval_start), to.min(interval_end));
+            if to <= from {
+                continue;
+            }
+            let seconds = (to - from) as f64 / 1000.0;
+            let weight = calculate_energy(&metrics, seconds, &settings.hardware_profile, settings.energy_model).total_kwh;
+            let intensity = intensity_at(profile, from + (to - from) / 2).unwrap_or(fallback);
+            weighted += weight * intensity;
+            total_weight += weight;
+        }
+    }
 
-    *session_guard = Some(session.clone());
+    (total_weight > 0.0).then(|| weighted / total_weight)
+}
 
-    Ok(serde_json::json!({
-        "sessionId": session.id,
-        "startTime": session.start_timestamp,
-        "message": "Profiling started"
-    }))
+/// Averages over the raw samples, so a merged entry counts once per sample
+/// it stands for.
+fn metrics_from_samples(samples: &[ResourceSample]) -> RunMetrics {
+    metrics_from_averages(
+        weighted_mean(samples, |s| s.cpu_utilization as f64) as f32,
+        weighted_mean(samples, |s| s.memory_percent as f64) as f32,
+        average_io(samples),
+    )
 }
 
-#[tauri::command]
-fn stop_profiling(app_handle: tauri::AppHandle, state: State<AppState>) -> Result<RunResult, String> {
-    let mut session_guard = state.profiling_session.lock().map_err(|e| e.to_string())?;
-    
-    if let Some(session) = session_guard.take() {
-        let duration_ms = Utc::now().timestamp_millis() - session.start_time;
-        #[allow(unused_variables)]
-        let duration_seconds = duration_ms as f64 / 1000.0;
-
-        // Calculate averages
-        let sample_count = session.samples.len();
-        let avg_cpu = if sample_count > 0 {
-            session.samples.iter().map(|s| s.cpu_utilization).sum::<f32>() / sample_count as f32
-        } else { 0.0 };
-        
-        let max_memory = if sample_count > 0 {
-             session.samples.iter().map(|s| s.memory_used).max().unwrap_or(0)
-        } else { 0 };
-
-        let avg_mem_percent = if sample_count > 0 {
-            session.samples.iter().map(|s| s.memory_percent).sum::<f32>() / sample_count as f32
-        } else { 0.0 };
-
-        // Disk & Net Averages (Bytes per sec)
-        let avg_disk_read = if sample_count > 0 {
-            session.samples.iter().map(|s| s.disk_read_per_sec).sum::<f64>() / sample_count as f64
-        } else { 0.0 };
-        let avg_disk_write = if sample_count > 0 {
-            session.samples.iter().map(|s| s.disk_write_per_sec).sum::<f64>() / sample_count as f64
-        } else { 0.0 };
-        
-        let avg_net_rx = if sample_count > 0 {
-            session.samples.iter().map(|s| s.net_rx_per_sec).sum::<f64>() / sample_count as f64
-        } else { 0.0 };
-        let avg_net_tx = if sample_count > 0 {
-            session.samples.iter().map(|s| s.net_tx_per_sec).sum::<f64>() / sample_count as f64
-        } else { 0.0 };
-
-        // Total MB
-        let total_disk_read_mb = (avg_disk_read * duration_seconds) / 1_048_576.0;
-        let total_disk_write_mb = (avg_disk_write * duration_seconds) / 1_048_576.0;
-        let total_net_recv_mb = (avg_net_rx * duration_seconds) / 1_048_576.0;
-        let total_net_sent_mb = (avg_net_tx * duration_seconds) / 1_048_576.0;
-
-        // Activity Estimates (0-100)
-        // Heuristic: 100% activity = 100 MB/s for Disk, 10 MB/s for Net (adjust as needed)
-        // Or better, just map >0 to some value or keep it proportional.
-        let disk_activity_percent = ((avg_disk_read + avg_disk_write) / 100_000_000.0 * 100.0).min(100.0) as f32;
-        let net_activity_percent = ((avg_net_rx + avg_net_tx) / 10_000_000.0 * 100.0).min(100.0) as f32;
-
-        let metrics = RunMetrics {
-            cpuUtilization: avg_cpu,
-            memoryUsagePercent: avg_mem_percent,
-            gpuUtilization: 0.0,
-            diskActivity: disk_activity_percent,
-            networkActivity: net_activity_percent,
-        };
+/// Count-weighted mean of `value` over `samples`; 0 when there are none.
+fn weighted_mean(samples: &[ResourceSample], value: impl Fn(&ResourceSample) -> f64) -> f64 {
+    let n: f64 = samples.iter().map(|s| s.count as f64).sum();
+    if n == 0.0 {
+        return 0.0;
+    }
+    samples.iter().map(|s| value(s) * s.count as f64).sum::<f64>() / n
+}
 
-        let settings = load_settings_from_disk(&app_handle);
-        let energy = calculate_energy(&metrics, duration_seconds, &settings.hardware_profile);
-        let carbon = calculate_carbon(energy.total_kwh, &settings.region, settings.pue);
-
-        let resources = RunResources {
-            wall_time: duration_seconds,
-            cpu_utilization: avg_cpu,
-            cpu_time_user: (avg_cpu as f64 / 100.0) * duration_seconds * 0.7,
-            cpu_time_system: (avg_cpu as f64 / 100.0) * duration_seconds * 0.3,
-            memory_peak_mb: max_memory,
-            memory_avg_percent: avg_mem_percent,
-            disk_read_mb: total_disk_read_mb,
-            disk_write_mb: total_disk_write_mb,
-            net_recv_mb: total_net_recv_mb,
-            net_sent_mb: total_net_sent_mb,
-            gpu_utilization: 0.0,
-        };
+fn metrics_from_averages(avg_cpu: f32, avg_mem_percent: f32, io: (f64, f64, f64, f64)) -> RunMetrics {
+    let (avg_disk_read, avg_disk_write, avg_net_rx, avg_net_tx) = io;
 
-        let result = RunResult {
-            id: session.id,
-            project: session.project,
-            command: session.command,
-            branch: session.branch,
-            commit: session.commit,
-            timestamp: session.start_timestamp,
-            resources,
-            metrics,
-            carbon,
-            energy,
-            sampleCount: sample_count,
-            durationMs: duration_ms,
-        };
-        
-        save_run_to_disk(&app_handle, &result);
+    // Activity Estimates (0-100)
+    // Heuristic: 100% activity = 100 MB/s for Disk, 10 MB/s for Net (adjust as needed)
+    let disk_activity_percent = ((avg_disk_read + avg_disk_write) / 100_000_000.0 * 100.0).min(100.0) as f32;
+    let net_activity_percent = ((avg_net_rx + avg_net_tx) / 10_000_000.0 * 100.0).min(100.0) as f32;
 
-        Ok(result)
-    } else {
-        Err("No profiling session in progress".to_string())
+    RunMetrics {
+        cpuUtilization: avg_cpu,
+        memoryUsagePercent: avg_mem_percent,
+        gpuUtilization: 0.0,
+        diskActivity: disk_activity_percent,
+        networkActivity: net_activity_percent,
     }
 }
 
-#[tauri::command]
-fn get_profiling_status(state: State<AppState>) -> serde_json::Value {
-    let session_guard = state.profiling_session.lock().unwrap();
-    
-    if let Some(session) = &*session_guard {
-        serde_json::json!({
-            "active": true,
-            "sessionId": session.id,
-            "startTime": session.start_timestamp,
-            "elapsedMs": Utc::now().timestamp_millis() - session.start_time
-        })
-    } else {
-        serde_json::json!({ "active": false })
+impl SampleBuffer {
+    /// Caps below `MIN_SESSION_SAMPLE_CAP`, e.g. from a hand-edited settings
+    /// file, are raised to it.
+    fn with_cap(cap: usize) -> Self {
+        SampleBuffer { cap: cap.max(MIN_SESSION_SAMPLE_CAP), ..Default::default() }
     }
-}
 
-#[tauri::command]
-fn get_runs(app_handle: tauri::AppHandle) -> Vec<RunResult> {
-    let path = get_runs_path(&app_handle);
-    if path.exists() {
-        let content = fs::read_to_string(&path).unwrap_or_else(|_| "[]".to_string());
-        serde_json::from_str(&content).unwrap_or_default()
-    } else {
-        Vec::new()
+    fn len(&self) -> usize {
+        self.totals.count
     }
-}
 
-#[derive(Serialize)]
-#[allow(non_snake_case)]
-struct StatsSummary {
-    totalRuns: usize,
-    totalCarbon: String,
-    totalEnergy: String,
-    avgCarbon: String,
-    trend: String,
+    fn push(&mut self, sample: ResourceSample) {
+        let totals = &mut self.totals;
+        totals.count += 1;
+        totals.cpu_sum += sample.cpu_utilization as f64;
+        totals.memory_percent_sum += sample.memory_percent as f64;
+        totals.disk_read_sum += sample.disk_read_per_sec;
+        totals.disk_write_sum += sample.disk_write_per_sec;
+        totals.net_rx_sum += sample.net_rx_per_sec;
+        totals.net_tx_sum += sample.net_tx_per_sec;
+        totals.memory_max = totals.memory_max.max(sample.memory_used);
+
+        self.entries.push(BufferedSample {
+            cpu_min: sample.cpu_utilization,
+            cpu_max: sample.cpu_utilization,
+            memory_max: sample.memory_used,
+            sample,
+        });
+        if self.entries.len() > self.cap.max(MIN_SESSION_SAMPLE_CAP) {
+            self.compact();
+        }
+    }
+
+    /// Merges the older half of the entries pairwise.
+    fn compact(&mut self) {
+        let older = self.entries.len() / 2;
+        let recent = self.entries.split_off(older);
+        let merged: Vec<BufferedSample> = self
+            .entries
+            .chunks(2)
+            .map(|pair| pair.iter().skip(1).fold(pair[0].clone(), |a, b| a.merge(b)))
+            .collect();
+        self.entries = merged;
+        self.entries.extend(recent);
+    }
+
+    /// Exact session averages from the running totals.
+    fn metrics(&self) -> RunMetrics {
+        let n = self.totals.count.max(1) as f64;
+        metrics_from_averages(
+            (self.totals.cpu_sum / n) as f32,
+            (self.totals.memory_percent_sum / n) as f32,
+            self.average_io(),
+        )
+    }
+
+    /// Average disk read/write and network rx/tx rates, in bytes per second.
+    fn average_io(&self) -> (f64, f64, f64, f64) {
+        let n = self.totals.count.max(1) as f64;
+        let t = &self.totals;
+        (t.disk_read_sum / n, t.disk_write_sum / n, t.net_rx_sum / n, t.net_tx_sum / n)
+    }
+
+    /// The series at its current resolution, for time weighting and storage.
+    fn to_samples(&self) -> Vec<ResourceSample> {
+        self.entries.iter().map(|e| e.sample.clone()).collect()
+    }
+}
+
+impl BufferedSample {
+    fn merge(self, other: &BufferedSample) -> BufferedSample {
+        let (a, b) = (self.sample.count as f64, other.sample.count as f64);
+        let n = a + b;
+        let avg = |x: f64, y: f64| (x * a + y * b) / n;
+        let (s, o) = (&self.sample, &other.sample);
+        BufferedSample {
+            sample: ResourceSample {
+                timestamp: avg(s.timestamp as f64, o.timestamp as f64).round() as i64,
+                cpu_utilization: avg(s.cpu_utilization as f64, o.cpu_utilization as f64) as f32,
+                memory_used: avg(s.memory_used as f64, o.memory_used as f64) as u64,
+                memory_percent: avg(s.memory_percent as f64, o.memory_percent as f64) as f32,
+                disk_read_per_sec: avg(s.disk_read_per_sec, o.disk_read_per_sec),
+                disk_write_per_sec: avg(s.disk_write_per_sec, o.disk_write_per_sec),
+                net_rx_per_sec: avg(s.net_rx_per_sec, o.net_rx_per_sec),
+                net_tx_per_sec: avg(s.net_tx_per_sec, o.net_tx_per_sec),
+                count: s.count + o.count,
+            },
+            cpu_min: self.cpu_min.min(other.cpu_min),
+            cpu_max: self.cpu_max.max(other.cpu_max),
+            memory_max: self.memory_max.max(other.memory_max),
+        }
+    }
+}
+
+fn average_io(samples: &[ResourceSample]) -> (f64, f64, f64, f64) {
+    (
+        weighted_mean(samples, |s| s.disk_read_per_sec),
+        weighted_mean(samples, |s| s.disk_write_per_sec),
+        weighted_mean(samples, |s| s.net_rx_per_sec),
+        weighted_mean(samples, |s| s.net_tx_per_sec),
+    )
+}
+
+/// Everything derived from a run's metrics under a given set of settings.
+struct RunImpact {
+    energy: EnergyResult,
+    carbon: CarbonResult,
+    cost: Option<CostResult>,
+    water: WaterResult,
+}
+
+fn derive_impact(
+    metrics: &RunMetrics,
+    duration_seconds: f64,
+    samples: &[ResourceSample],
+    active: &[(i64, i64)],
+    entry: Option<&CarbonIntensityEntry>,
+    settings: &AppSettings,
+) -> RunImpact {
+    let energy = calculate_energy(metrics, duration_seconds, &settings.hardware_profile, settings.energy_model);
+    let carbon = calculate_carbon_timed(&energy, samples, active, entry, settings);
+    let grid_kwh = energy.total_kwh * settings.pue - carbon.onsite_kwh;
+    let cost = settings.tariff.as_ref().map(|tariff| {
+        calculate_cost(grid_kwh, tariff, samples, active, settings)
+    });
+    let water = calculate_water(energy.total_kwh, grid_kwh, settings);
+    RunImpact { energy, carbon, cost, water }
+}
+
+/// Turns a session ending at `end_ms` into a `RunResult`. Shared by the
+/// command, tray and shortcut paths and by crash recovery.
+fn finalize_session(app_handle: &tauri::AppHandle, session: &ProfilingSession, end_ms: i64) -> Result<RunResult, CarbonLintError> {
+    let paused_ms = paused_ms_between(&session_pauses(session, end_ms), session.start_time, end_ms);
+    let excluded = session_excluded(session, end_ms);
+    let duration_ms = end_ms - session.start_time - paused_ms_between(&excluded, session.start_time, end_ms);
+    let duration_seconds = duration_ms as f64 / 1000.0;
+
+    let sample_count = session.samples.len();
+    let metrics = session.samples.metrics();
+    let avg_cpu = metrics.cpuUtilization;
+    let avg_mem_percent = metrics.memoryUsagePercent;
+    let max_memory = session.samples.totals.memory_max;
+    let samples = session.samples.to_samples();
+
+    // Total MB
+    let (avg_disk_read, avg_disk_write, avg_net_rx, avg_net_tx) = session.samples.average_io();
+    let total_disk_read_mb = (avg_disk_read * duration_seconds) / 1_048_576.0;
+    let total_disk_write_mb = (avg_disk_write * duration_seconds) / 1_048_576.0;
+    let total_net_recv_mb = (avg_net_rx * duration_seconds) / 1_048_576.0;
+    let total_net_sent_mb = (avg_net_tx * duration_seconds) / 1_048_576.0;
+
+    let settings = current_settings(app_handle)?;
+    let mut intensity_map = load_intensity_map(app_handle);
+    let reading = resolve_intensity(app_handle, &settings);
+    intensity_map
+        .entry(settings.region.clone())
+        .or_insert_with(|| CarbonIntensityEntry {
+            region: settings.region.clone(),
+            gco2_kwh: reading.gco2_kwh,
+            marginal_gco2_kwh: None,
+            hourly_profile: None,
+        })
+        .gco2_kwh = reading.gco2_kwh;
+    let RunImpact { energy, mut carbon, cost, water } = derive_impact(
+        &metrics,
+        duration_seconds,
+        &samples,
+        &active_spans(&excluded, session.start_time, end_ms),
+        intensity_map.get(&settings.region),
+        &settings,
+    );
+    carbon.intensity_provider = reading.provider;
+    carbon.intensity_timestamp = reading.timestamp;
+
+    let phases = phase_spans(&session.phases, session.start_time, end_ms)
+        .into_iter()
+        .map(|(name, start, end)| {
+            phase_result(name, start, end, &samples, &excluded, intensity_map.get(&settings.region), &settings)
+        })
+        .collect();
+
+    let resources = RunResources {
+        wall_time: duration_seconds,
+        cpu_utilization: avg_cpu,
+        cpu_time_user: (avg_cpu as f64 / 100.0) * duration_seconds * 0.7,
+        cpu_time_system: (avg_cpu as f64 / 100.0) * duration_seconds * 0.3,
+        memory_peak_mb: max_memory,
+        memory_avg_percent: avg_mem_percent,
+        disk_read_mb: total_disk_read_mb,
+        disk_write_mb: total_disk_write_mb,
+        net_recv_mb: total_net_recv_mb,
+        net_sent_mb: total_net_sent_mb,
+        gpu_utilization: 0.0,
+    };
+
+    Ok(RunResult {
+        schemaVersion: RUN_SCHEMA_VERSION,
+        id: session.id.clone(),
+        project: session.project.clone(),
+        command: session.command.clone(),
+        branch: session.branch.clone(),
+        commit: session.commit.clone(),
+        timestamp: session.start_timestamp.clone(),
+        resources,
+        metrics,
+        carbon,
+        energy,
+        sampleCount: sample_count,
+        durationMs: duration_ms,
+        cost,
+        water,
+        hardwareProfile: settings.hardware_profile,
+        revisions: Vec::new(),
+        truncatedAt: None,
+        pausedMs: paused_ms,
+        pauses: session_pauses(session, end_ms)
+            .iter()
+            .map(|pause| RunPause { start: millis_to_rfc3339(pause.start), end: millis_to_rfc3339(pause.end) })
+            .collect(),
+        phases,
+        stopReason: None,
+        gaps: session
+            .gaps
+            .iter()
+            .map(|gap| RunGap {
+                start: millis_to_rfc3339(gap.start),
+                end: millis_to_rfc3339(gap.end),
+                duration_ms: gap.end - gap.start,
+                kind: gap.kind,
+            })
+            .collect(),
+        trigger: session.trigger,
+        repository: session.repository.clone(),
+    })
+}
+
+fn millis_to_rfc3339(ms: i64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(ms).map(|t| t.to_rfc3339()).unwrap_or_default()
+}
+
+/// The session's pauses up to `end_ms`, including one still open.
+fn session_pauses(session: &ProfilingSession, end_ms: i64) -> Vec<PauseInterval> {
+    let mut pauses = session.pauses.clone();
+    if let Some(start) = session.paused_at {
+        pauses.push(PauseInterval { start, end: end_ms });
+    }
+    pauses
+}
+
+/// Pauses plus suspend and clock-jump gaps: all the time that doesn't count
+/// towards the session. They never overlap, since gaps aren't recorded
+/// while paused.
+fn session_excluded(session: &ProfilingSession, end_ms: i64) -> Vec<PauseInterval> {
+    let mut excluded = session_pauses(session, end_ms);
+    excluded.extend(session.gaps.iter().map(|gap| PauseInterval { start: gap.start, end: gap.end }));
+    excluded
+}
+
+/// The stretches of `[start_ms, end_ms)` not covered by `excluded`, in order.
+fn active_spans(excluded: &[PauseInterval], start_ms: i64, end_ms: i64) -> Vec<(i64, i64)> {
+    let mut cuts: Vec<&PauseInterval> = excluded.iter().filter(|p| p.end > start_ms && p.start < end_ms).collect();
+    cuts.sort_by_key(|p| p.start);
+    let mut spans = Vec::new();
+    let mut cursor = start_ms;
+    for cut in cuts {
+        if cut.start > cursor {
+            spans.push((cursor, cut.start));
+        }
+        cursor = cursor.max(cut.end);
+    }
+    if cursor < end_ms {
+        spans.push((cursor, end_ms));
+    }
+    spans
+}
+
+/// Milliseconds of `[start_ms, end_ms)` covered by `pauses`.
+fn paused_ms_between(pauses: &[PauseInterval], start_ms: i64, end_ms: i64) -> i64 {
+    pauses
+        .iter()
+        .map(|p| (p.end.min(end_ms) - p.start.max(start_ms)).max(0))
+        .sum()
+}
+
+/// `(name, start, end)` for each phase. Time before the first marker, if
+/// any, becomes an "initial" phase.
+fn phase_spans(markers: &[PhaseMarker], start_ms: i64, end_ms: i64) -> Vec<(String, i64, i64)> {
+    let Some(first) = markers.first() else {
+        return Vec::new();
+    };
+    let mut spans = Vec::new();
+    if first.timestamp > start_ms {
+        spans.push(("initial".to_string(), start_ms, first.timestamp));
+    }
+    for (i, marker) in markers.iter().enumerate() {
+        let end = markers.get(i + 1).map(|next| next.timestamp).unwrap_or(end_ms);
+        spans.push((marker.name.clone(), marker.timestamp, end));
+    }
+    spans
+}
+
+/// Energy and carbon for one phase, from the samples taken within it. A
+/// sample describes the interval ending at its timestamp, so one taken
+/// exactly at a marker belongs to the phase before it.
+fn phase_result(
+    name: String,
+    start_ms: i64,
+    end_ms: i64,
+    samples: &[ResourceSample],
+    excluded: &[PauseInterval],
+    entry: Option<&CarbonIntensityEntry>,
+    settings: &AppSettings,
+) -> PhaseResult {
+    let active_ms = end_ms - start_ms - paused_ms_between(excluded, start_ms, end_ms);
+    let covered: Vec<ResourceSample> = samples
+        .iter()
+        .filter(|s| s.timestamp > start_ms && s.timestamp <= end_ms)
+        .cloned()
+        .collect();
+    let metrics = metrics_from_samples(&covered);
+    let active = active_spans(excluded, start_ms, end_ms);
+    let impact = derive_impact(&metrics, active_ms as f64 / 1000.0, &covered, &active, entry, settings);
+    PhaseResult {
+        name,
+        start: millis_to_rfc3339(start_ms),
+        end: millis_to_rfc3339(end_ms),
+        duration_ms: active_ms,
+        sample_count: covered.iter().map(|s| s.count as usize).sum(),
+        energy_kwh: impact.energy.total_kwh,
+        carbon_grams: impact.carbon.total_grams,
+    }
+}
+
+/// On-site water scales with IT energy; off-site water with the grid
+/// energy drawn after PUE and on-site generation.
+fn calculate_water(energy_kwh: f64, grid_kwh: f64, settings: &AppSettings) -> WaterResult {
+    let onsite_liters = energy_kwh * settings.wue;
+    let offsite_liters = grid_kwh * settings.offsite_water_intensity;
+    WaterResult {
+        total_liters: onsite_liters + offsite_liters,
+        onsite_liters,
+        offsite_liters,
+        wue: settings.wue,
+        offsite_liters_per_kwh: settings.offsite_water_intensity,
+    }
+}
+
+fn calculate_cost(
+    billed_kwh: f64,
+    tariff: &Tariff,
+    samples: &[ResourceSample],
+    active: &[(i64, i64)],
+    settings: &AppSettings,
+) -> CostResult {
+    let tou_price = tariff.time_of_use.as_ref().and_then(|prices| {
+        let profile = IntensityProfile::TypicalDay {
+            hours: prices.clone(),
+            utc_offset_minutes: tariff.utc_offset_minutes,
+        };
+        profile_weighted_intensity(&profile, tariff.price_per_kwh, samples, active, settings)
+    });
+    let price_per_kwh = tou_price.unwrap_or(tariff.price_per_kwh);
+
+    CostResult {
+        currency: tariff.currency.clone(),
+        price_per_kwh,
+        billed_kwh,
+        total_cost: billed_kwh * price_per_kwh,
+        time_of_use: tou_price.is_some(),
+    }
+}
+
+// ============================================
+// Recomputation
+// ============================================
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct RunFilter {
+    project: Option<String>,
+    branch: Option<String>,
+    commit: Option<String>,
+    command: Option<String>,
+    /// Inclusive RFC 3339 bounds on the run's start time.
+    since: Option<String>,
+    until: Option<String>,
+    /// Most recent runs first instead of oldest first.
+    #[serde(default)]
+    newest_first: bool,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// Settings a caller may swap out when re-deriving a run.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct SettingsOverrides {
+    region: Option<String>,
+    hardware_profile: Option<String>,
+    pue: Option<f64>,
+    energy_model: Option<EnergyModel>,
+}
+
+impl SettingsOverrides {
+    fn apply(&self, settings: &AppSettings) -> AppSettings {
+        let mut settings = settings.clone();
+        if let Some(region) = &self.region {
+            settings.region = region.clone();
+        }
+        if let Some(hardware_profile) = &self.hardware_profile {
+            settings.hardware_profile = hardware_profile.clone();
+        }
+        if let Some(pue) = self.pue {
+            settings.pue = pue;
+        }
+        if let Some(energy_model) = self.energy_model {
+            settings.energy_model = energy_model;
+        }
+        settings
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RecomputeOptions {
+    /// A single run; takes precedence over `filter`. With neither, every run.
+    run_id: Option<String>,
+    filter: Option<RunFilter>,
+    #[serde(flatten)]
+    overrides: SettingsOverrides,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SimulationScenario {
+    /// Label for the dashboard's comparison table.
+    name: Option<String>,
+    #[serde(flatten)]
+    overrides: SettingsOverrides,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ScenarioResult {
+    name: Option<String>,
+    figures: RunFigures,
+    energy: EnergyResult,
+    carbon: CarbonResult,
+    cost: Option<CostResult>,
+    water: WaterResult,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SimulationReport {
+    run_id: String,
+    baseline: RunFigures,
+    scenarios: Vec<ScenarioResult>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RunFigures {
+    region: String,
+    hardware_profile: String,
+    pue: f64,
+    energy_kwh: f64,
+    carbon_grams: f64,
+    water_liters: f64,
+    cost: Option<f64>,
 }
 
+impl RunFigures {
+    fn of(run: &RunResult) -> Self {
+        RunFigures {
+            region: run.carbon.region.clone(),
+            hardware_profile: run.hardwareProfile.clone(),
+            pue: run.carbon.pue,
+            energy_kwh: run.energy.total_kwh,
+            carbon_grams: run.carbon.total_grams,
+            water_liters: run.water.total_liters,
+            cost: run.cost.as_ref().map(|c| c.total_cost),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RecomputeDiff {
+    id: String,
+    before: RunFigures,
+    after: RunFigures,
+}
+
+/// Re-derives a run's energy, carbon, cost and water under `settings`,
+/// pushing the previous figures onto its revision history.
+fn recompute_run(
+    run: &mut RunResult,
+    samples: &[ResourceSample],
+    settings: &AppSettings,
+    intensity_map: &HashMap<String, CarbonIntensityEntry>,
+) {
+    let start_ms = DateTime::parse_from_rfc3339(&run.timestamp)
+        .map(|t| t.timestamp_millis())
+        .unwrap_or(0);
+    let end_ms = start_ms + run.durationMs + run.pausedMs + run.gaps.iter().map(|g| g.duration_ms).sum::<i64>();
+    let duration_seconds = run.durationMs as f64 / 1000.0;
+    let bound = |t: &str| DateTime::parse_from_rfc3339(t).map(|t| t.timestamp_millis()).unwrap_or(0);
+    let excluded: Vec<PauseInterval> = run
+        .pauses
+        .iter()
+        .map(|p| (&p.start, &p.end))
+        .chain(run.gaps.iter().map(|g| (&g.start, &g.end)))
+        .map(|(start, end)| PauseInterval { start: bound(start), end: bound(end) })
+        .collect();
+
+    // A live provider reading can't be fetched again, so keep the stored
+    // base intensity when the region is unchanged.
+    let same_region = intensity_map
+        .get(&settings.region)
+        .map(|e| e.region == run.carbon.region)
+        .unwrap_or(false);
+    let mut entry = intensity_map.get(&settings.region).cloned();
+    let keep_reading = same_region && run.carbon.intensity_provider != default_provider_name();
+    if let (Some(entry), true) = (entry.as_mut(), keep_reading) {
+        entry.gco2_kwh = run.carbon.average_intensity;
+    }
+    // Without samples the profile can't be re-weighted, so keep the stored
+    // time-weighted average rather than dropping to the region constant.
+    let keep_weighted = same_region
+        && samples.is_empty()
+        && run.carbon.time_varying
+        && entry.as_ref().is_some_and(|e| e.hourly_profile.is_some());
+    if let (Some(entry), true) = (entry.as_mut(), keep_weighted) {
+        entry.gco2_kwh = run.carbon.average_intensity;
+        entry.hourly_profile = None;
+    }
+
+    // Without stored samples, fall back to the aggregate metrics; intervals
+    // are then not re-weighted by time of day.
+    let metrics = if samples.is_empty() { run.metrics.clone() } else { metrics_from_samples(samples) };
+    let RunImpact { energy, mut carbon, cost, water } = derive_impact(
+        &metrics,
+        duration_seconds,
+        samples,
+        &active_spans(&excluded, start_ms, end_ms),
+        entry.as_ref(),
+        settings,
+    );
+    if keep_reading {
+        carbon.intensity_provider = run.carbon.intensity_provider.clone();
+        carbon.intensity_timestamp = run.carbon.intensity_timestamp.clone();
+    }
+    if keep_weighted {
+        carbon.time_varying = true;
+    }
+
+    for phase in run.phases.iter_mut() {
+        if samples.is_empty() {
+            // No samples to split, so keep each phase's share of the totals.
+            let scale = |new: f64, old: f64| if old > 0.0 { new / old } else { 0.0 };
+            phase.energy_kwh *= scale(energy.total_kwh, run.energy.total_kwh);
+            phase.carbon_grams *= scale(carbon.total_grams, run.carbon.total_grams);
+            continue;
+        }
+        *phase = phase_result(
+            std::mem::take(&mut phase.name),
+            bound(&phase.start),
+            bound(&phase.end),
+            samples,
+            &excluded,
+            entry.as_ref(),
+            settings,
+        );
+    }
+
+    run.revisions.push(RunRevision {
+        revised_at: Utc::now().to_rfc3339(),
+        hardware_profile: run.hardwareProfile.clone(),
+        energy: std::mem::replace(&mut run.energy, energy),
+        carbon: std::mem::replace(&mut run.carbon, carbon),
+        cost: std::mem::replace(&mut run.cost, cost),
+        water: std::mem::replace(&mut run.water, water),
+    });
+    run.hardwareProfile = settings.hardware_profile.clone();
+}
+
+// ============================================
+// Commands
+// ============================================
+
 #[tauri::command]
-fn get_stats_summary(app_handle: tauri::AppHandle) -> StatsSummary {
-    let runs = get_runs(app_handle);
-    let total_runs = runs.len();
-    
-    let total_carbon: f64 = runs.iter().map(|r| r.carbon.total_grams).sum();
-    let total_energy: f64 = runs.iter().map(|r| r.energy.total_kwh).sum();
-    
-    let avg_carbon = if total_runs > 0 {
-        total_carbon / total_runs as f64
+fn get_current_stats(state: State<AppState>) -> Result<SystemStats, CarbonLintError> {
+    let mut sys = state.sys.lock()?;
+    sys.refresh_cpu();
+    sys.refresh_memory();
+
+    let cpu_usage = sys.global_cpu_info().cpu_usage();
+    let total_mem = sys.total_memory() / 1024 / 1024;
+    let used_mem = sys.used_memory() / 1024 / 1024;
+    let free_mem = sys.free_memory() / 1024 / 1024;
+    let mem_percent = if total_mem > 0 {
+        (used_mem as f32 / total_mem as f32) * 100.0
     } else {
         0.0
     };
 
-    let trend = if total_runs >= 2 {
-        let last = runs.last().unwrap().carbon.total_grams;
-        let prev = runs[runs.len() - 2].carbon.total_grams;
-        if prev > 0.0 {
-            ((last - prev) / prev) * 100.0
-        } else {
-            0.0
+    let slow = state.slow_metrics.lock()?.clone();
+
+    Ok(SystemStats {
+        timestamp: Utc::now().to_rfc3339(),
+        cpu: CpuStats {
+            utilization: cpu_usage,
+            cores: sys.cpus().len(),
+        },
+        memory: MemoryStats {
+            total_mb: total_mem,
+            used_mb: used_mem,
+            free_mb: free_mem,
+            usage_percent: mem_percent,
+            total: sys.total_memory(),
+        },
+        disk: slow.disk,
+        network: slow.network,
+        gpu: slow.gpu,
+        uptime: System::uptime(),
+    })
+}
+
+/// Git detection shells out to `git`, so starts stay off the main thread.
+#[tauri::command]
+async fn start_profiling(app_handle: tauri::AppHandle, options: StartProfilingOptions) -> Result<serde_json::Value, CarbonLintError> {
+    run_blocking(move || {
+        let state = app_handle.state::<AppState>();
+        start_profiling_impl(&app_handle, &state, options).map(|session| session_started(&session))
+    })
+    .await
+}
+
+pub(crate) fn session_started(session: &ProfilingSession) -> serde_json::Value {
+    serde_json::json!({
+        "session": session.name,
+        "sessionId": session.id,
+        "startTime": session.start_timestamp,
+        "message": "Profiling started"
+    })
+}
+
+#[tauri::command]
+async fn stop_profiling(app_handle: tauri::AppHandle, session: Option<String>) -> Result<RunResult, CarbonLintError> {
+    run_blocking(move || {
+        let state = app_handle.state::<AppState>();
+        stop_profiling_impl(&app_handle, &state, session.as_deref().unwrap_or(DEFAULT_SESSION), StopReason::Manual)
+    })
+    .await
+}
+
+/// Runs work that may wait on the network or disk, such as a provider
+/// request, on a blocking thread instead of the main thread that sync
+/// commands use.
+async fn run_blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, CarbonLintError> + Send + 'static,
+) -> Result<T, CarbonLintError> {
+    tauri::async_runtime::spawn_blocking(work).await?
+}
+
+fn session_status(session: &ProfilingSession, now: i64) -> serde_json::Value {
+    let excluded_ms = paused_ms_between(&session_excluded(session, now), session.start_time, now);
+    serde_json::json!({
+        "active": true,
+        "session": session.name,
+        "sessionId": session.id,
+        "command": session.command,
+        "project": session.project,
+        "startTime": session.start_timestamp,
+        "elapsedMs": now - session.start_time - excluded_ms,
+        "paused": session.paused_at.is_some(),
+        "phase": session.phases.last().map(|p| p.name.clone())
+    })
+}
+
+/// The default session's status at the top level, as the dashboard expects,
+/// plus every active session under `sessions`.
+#[tauri::command]
+fn get_profiling_status(state: State<AppState>) -> Result<serde_json::Value, CarbonLintError> {
+    let sessions = state.profiling_sessions.lock()?;
+    let now = Utc::now().timestamp_millis();
+
+    let mut all: Vec<&ProfilingSession> = sessions.values().collect();
+    all.sort_by_key(|s| s.start_time);
+    let mut status = sessions
+        .get(DEFAULT_SESSION)
+        .map(|s| session_status(s, now))
+        .unwrap_or_else(|| serde_json::json!({ "active": false }));
+    status["sessions"] = all.into_iter().map(|s| session_status(s, now)).collect();
+    Ok(status)
+}
+
+/// Applies `update` to the named (or default) active session.
+fn with_session<T>(
+    state: &State<AppState>,
+    session: Option<String>,
+    update: impl FnOnce(&mut ProfilingSession) -> Result<T, CarbonLintError>,
+) -> Result<T, CarbonLintError> {
+    let name = session.unwrap_or_else(default_session_name);
+    let mut sessions = state.profiling_sessions.lock()?;
+    update(sessions.get_mut(&name).ok_or_else(|| no_session(&name))?)
+}
+
+#[tauri::command]
+fn pause_profiling(state: State<AppState>, session: Option<String>) -> Result<serde_json::Value, CarbonLintError> {
+    with_session(&state, session, |session| {
+        if session.paused_at.is_some() {
+            return Err(CarbonLintError::SessionState("Profiling session is already paused".to_string()));
+        }
+        session.paused_at = Some(Utc::now().timestamp_millis());
+        Ok(serde_json::json!({ "success": true }))
+    })
+}
+
+#[tauri::command]
+fn resume_profiling(state: State<AppState>, session: Option<String>) -> Result<serde_json::Value, CarbonLintError> {
+    with_session(&state, session, |session| {
+        let start = session
+            .paused_at
+            .take()
+            .ok_or_else(|| CarbonLintError::SessionState("Profiling session is not paused".to_string()))?;
+        session.pauses.push(PauseInterval { start, end: Utc::now().timestamp_millis() });
+        Ok(serde_json::json!({ "success": true }))
+    })
+}
+
+/// Starts a new named phase; the previous one ends here.
+#[tauri::command]
+fn mark_phase(state: State<AppState>, session: Option<String>, name: String) -> Result<serde_json::Value, CarbonLintError> {
+    if name.trim().is_empty() {
+        return Err(CarbonLintError::InvalidInput("Phase name cannot be empty".to_string()));
+    }
+    with_session(&state, session, |session| {
+        session.phases.push(PhaseMarker { name, timestamp: Utc::now().timestamp_millis() });
+        Ok(serde_json::json!({ "success": true }))
+    })
+}
+
+#[tauri::command]
+fn get_recovered_sessions(app_handle: tauri::AppHandle) -> Result<Vec<RecoveredSession>, CarbonLintError> {
+    let mut sessions = Vec::new();
+    for entry in fs::read_dir(get_sessions_dir(&app_handle)?)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let Some(id) = name.strip_suffix(".recovered.json") else {
+            continue;
+        };
+        match load_recovered_session(&app_handle, id) {
+            Ok(SessionCheckpoint { session, checkpointed_at }) => sessions.push(RecoveredSession {
+                id: session.id,
+                project: session.project,
+                command: session.command,
+                branch: session.branch,
+                commit: session.commit,
+                start_time: session.start_timestamp,
+                checkpointed_at: millis_to_rfc3339(checkpointed_at),
+                sample_count: session.samples.len(),
+            }),
+            Err(e) => {
+                if let Some(issues) = app_handle.try_state::<Arc<StorageIssues>>() {
+                    issues.report("sessions", Some(id), e.to_string());
+                }
+            }
         }
+    }
+    sessions.sort_by(|a, b| a.start_time.cmp(&b.start_time));
+    Ok(sessions)
+}
+
+/// Saves a recovered session as a run ending at its last checkpoint.
+#[tauri::command]
+async fn finalize_recovered_session(app_handle: tauri::AppHandle, id: String) -> Result<RunResult, CarbonLintError> {
+    run_blocking(move || {
+        let SessionCheckpoint { session, checkpointed_at } = load_recovered_session(&app_handle, &id)?;
+        let mut result = finalize_session(&app_handle, &session, checkpointed_at)?;
+        result.truncatedAt = Some(millis_to_rfc3339(checkpointed_at));
+        save_run_to_disk(&app_handle, &result, &session.samples.to_samples())?;
+        fs::remove_file(recovered_session_path(&app_handle, &id)?)?;
+        Ok(result)
+    })
+    .await
+}
+
+#[tauri::command]
+fn discard_recovered_session(app_handle: tauri::AppHandle, id: String) -> Result<serde_json::Value, CarbonLintError> {
+    let path = recovered_session_path(&app_handle, &id)?;
+    if !path.exists() {
+        return Err(CarbonLintError::NotFound(format!("No recovered session {}", id)));
+    }
+    fs::remove_file(path)?;
+    Ok(serde_json::json!({ "success": true }))
+}
+
+#[tauri::command]
+fn get_runs(app_handle: tauri::AppHandle, filter: Option<RunFilter>) -> Result<Vec<RunResult>, CarbonLintError> {
+    app_handle.state::<RunStore>().query(&filter.unwrap_or_default())
+}
+
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct StatsSummary {
+    totalRuns: usize,
+    totalCarbon: String,
+    totalEnergy: String,
+    totalWater: String,
+    avgCarbon: String,
+    trend: String,
+    costCurrency: String,
+    totalCost: String,
+    costToday: String,
+    costThisWeek: String,
+    costThisMonth: String,
+}
+
+#[tauri::command]
+fn get_stats_summary(app_handle: tauri::AppHandle) -> Result<StatsSummary, CarbonLintError> {
+    let settings = current_settings(&app_handle)?;
+    // Costs are only summed for runs billed in the current tariff's currency.
+    let currency = settings.tariff.map(|t| t.currency).unwrap_or_default();
+    let today = Local::now().date_naive();
+    let periods = [
+        today,
+        today - chrono::Days::new(today.weekday().num_days_from_monday().into()),
+        today.with_day(1).unwrap_or(today),
+    ];
+    let totals = app_handle.state::<RunStore>().totals(&currency, periods.map(local_day_start_ms))?;
+    let total_runs = totals.runs;
+
+    let avg_carbon = if total_runs > 0 {
+        totals.carbon_grams / total_runs as f64
     } else {
         0.0
     };
 
-    StatsSummary {
+    let trend = match totals.latest_carbon[..] {
+        [last, prev] if prev > 0.0 => ((last - prev) / prev) * 100.0,
+        _ => 0.0,
+    };
+    let (total_carbon, total_energy, total_water) = (totals.carbon_grams, totals.energy_kwh, totals.water_liters);
+    let (total_cost, [cost_today, cost_week, cost_month]) = (totals.cost, totals.cost_in_periods);
+
+    Ok(StatsSummary {
         totalRuns: total_runs,
         totalCarbon: format!("{:.2}", total_carbon),
         totalEnergy: format!("{:.5}", total_energy),
+        totalWater: format!("{:.3}", total_water),
         avgCarbon: format!("{:.2}", avg_carbon),
         trend: format!("{:.1}", trend),
+        costCurrency: currency,
+        totalCost: format!("{:.2}", total_cost),
+        costToday: format!("{:.2}", cost_today),
+        costThisWeek: format!("{:.2}", cost_week),
+        costThisMonth: format!("{:.2}", cost_month),
+    })
+}
+
+/// Epoch milliseconds of the first local instant of `day`. Where a time
+/// zone change skips midnight, the day starts an hour later.
+fn local_day_start_ms(day: NaiveDate) -> i64 {
+    [0, 1]
+        .into_iter()
+        .find_map(|hour| day.and_hms_opt(hour, 0, 0)?.and_local_timezone(Local).earliest())
+        .map_or(0, |start| start.timestamp_millis())
+}
+
+#[tauri::command]
+fn get_run(app_handle: tauri::AppHandle, id: String) -> Result<Option<RunResult>, CarbonLintError> {
+    app_handle.state::<RunStore>().get(&id)
+}
+
+#[tauri::command]
+fn get_run_samples(app_handle: tauri::AppHandle, id: String, max_points: Option<usize>) -> Result<Vec<ResourceSample>, CarbonLintError> {
+    let samples = app_handle
+        .state::<RunStore>()
+        .load_samples(&id)?
+        .ok_or_else(|| CarbonLintError::NotFound(format!("No samples stored for run {}", id)))?;
+    Ok(downsample(&samples, max_points.unwrap_or(0)))
+}
+
+#[tauri::command]
+fn delete_run(app_handle: tauri::AppHandle, id: String) -> Result<serde_json::Value, CarbonLintError> {
+    if !app_handle.state::<RunStore>().delete(&id)? {
+        return Err(CarbonLintError::NotFound(format!("Run {} not found", id)));
     }
+    Ok(serde_json::json!({ "success": true }))
 }
 
 #[tauri::command]
-fn get_run(app_handle: tauri::AppHandle, id: String) -> Option<RunResult> {
-    let runs = get_runs(app_handle);
-    runs.into_iter().find(|r| r.id == id)
+async fn suggest_run_window(
+    app_handle: tauri::AppHandle,
+    options: SuggestRunWindowOptions,
+) -> Result<RunWindowSuggestion, CarbonLintError> {
+    run_blocking(move || plan_run_window(&app_handle, options)).await
+}
+
+fn plan_run_window(app_handle: &tauri::AppHandle, options: SuggestRunWindowOptions) -> Result<RunWindowSuggestion, CarbonLintError> {
+    let settings = current_settings(app_handle)?;
+    let invalid = |message: &str| CarbonLintError::InvalidInput(message.to_string());
+
+    let history = match (&options.command, options.duration_minutes, options.energy_kwh) {
+        (Some(command), None, _) | (Some(command), _, None) => {
+            let filter = RunFilter { command: Some(command.clone()), project: options.project.clone(), ..Default::default() };
+            past_run_averages(&app_handle.state::<RunStore>().query(&filter)?, command, options.project.as_deref())
+        }
+        _ => None,
+    };
+    let duration_minutes = options.duration_minutes.or(history.map(|h| h.0))
+        .ok_or_else(|| invalid("Give a duration or a command with past runs"))?;
+    let energy_kwh = options.energy_kwh.or(history.map(|h| h.1))
+        .ok_or_else(|| invalid("Give an energy estimate or a command with past runs"))?;
+
+    let deadline = DateTime::parse_from_rfc3339(&options.deadline)
+        .map_err(|e| CarbonLintError::InvalidInput(format!("Invalid deadline: {}", e)))?
+        .timestamp_millis();
+    let now_ms = Utc::now().timestamp_millis();
+    let duration_ms = (duration_minutes * 60_000.0) as i64;
+    let candidates = window_candidates(now_ms, duration_ms, deadline, options.step_minutes.saturating_mul(60_000))?;
+
+    let (source, forecast) = load_intensity_forecast(app_handle, &settings)?;
+    let fallback = resolve_intensity(app_handle, &settings).gco2_kwh;
+
+    let now_intensity = mean_intensity(&forecast, now_ms, duration_ms, fallback);
+    let (best_start, best_intensity) =
+        lowest_intensity_start(&forecast, &candidates, duration_ms, fallback).unwrap_or((now_ms, now_intensity));
+
+    let now_carbon = energy_kwh * settings.pue * now_intensity;
+    let best_carbon = energy_kwh * settings.pue * best_intensity;
+    let to_rfc3339 = |ms: i64| {
+        DateTime::<Utc>::from_timestamp_millis(ms).map(|t| t.to_rfc3339()).unwrap_or_default()
+    };
+
+    Ok(RunWindowSuggestion {
+        start: to_rfc3339(best_start),
+        end: to_rfc3339(best_start + duration_ms),
+        duration_minutes,
+        energy_kwh,
+        forecast_source: source,
+        expected_intensity: best_intensity,
+        expected_carbon_grams: best_carbon,
+        now_intensity,
+        now_carbon_grams: now_carbon,
+        savings_grams: now_carbon - best_carbon,
+        savings_percent: if now_carbon > 0.0 { (now_carbon - best_carbon) / now_carbon * 100.0 } else { 0.0 },
+    })
 }
 
 #[tauri::command]
-fn delete_run(app_handle: tauri::AppHandle, id: String) -> Result<serde_json::Value, String> {
-    let path = get_runs_path(&app_handle);
-    let mut runs: Vec<RunResult> = if path.exists() {
-        let content = fs::read_to_string(&path).unwrap_or_else(|_| "[]".to_string());
-        serde_json::from_str(&content).unwrap_or_default()
-    } else {
-        return Err("No runs file found".to_string());
+fn recompute_runs(app_handle: tauri::AppHandle, options: RecomputeOptions) -> Result<Vec<RecomputeDiff>, CarbonLintError> {
+    let settings = options.overrides.apply(&current_settings(&app_handle)?);
+    let intensity_map = load_intensity_map(&app_handle);
+
+    let store = app_handle.state::<RunStore>();
+    let mut runs = match (&options.run_id, &options.filter) {
+        (Some(id), _) => store.get(id)?.into_iter().collect(),
+        (None, filter) => store.query(&filter.clone().unwrap_or_default())?,
     };
-    let before = runs.len();
-    runs.retain(|r| r.id != id);
-    if runs.len() == before {
-        return Err(format!("Run {} not found", id));
+    let mut diffs = Vec::new();
+    for run in runs.iter_mut() {
+        let before = RunFigures::of(run);
+        let samples = store.samples_or_empty(&run.id)?;
+        recompute_run(run, &samples, &settings, &intensity_map);
+        diffs.push(RecomputeDiff { id: run.id.clone(), before, after: RunFigures::of(run) });
     }
-    let json = serde_json::to_string_pretty(&runs).unwrap();
-    fs::write(path, json).map_err(|e| e.to_string())?;
-    Ok(serde_json::json!({ "success": true }))
+
+    if let (Some(id), true) = (&options.run_id, diffs.is_empty()) {
+        return Err(CarbonLintError::NotFound(format!("Run {} not found", id)));
+    }
+    if !options.dry_run && !diffs.is_empty() {
+        store.save_all(&runs)?;
+    }
+    Ok(diffs)
 }
 
 #[tauri::command]
-fn get_settings(app_handle: tauri::AppHandle) -> AppSettings {
-    load_settings_from_disk(&app_handle)
+fn simulate_run(
+    app_handle: tauri::AppHandle,
+    run_id: String,
+    scenarios: Vec<SimulationScenario>,
+) -> Result<SimulationReport, CarbonLintError> {
+    let run = get_run(app_handle.clone(), run_id.clone())?
+        .ok_or_else(|| CarbonLintError::NotFound(format!("Run {} not found", run_id)))?;
+    let settings = current_settings(&app_handle)?;
+    let intensity_map = load_intensity_map(&app_handle);
+    let samples = app_handle.state::<RunStore>().samples_or_empty(&run_id)?;
+
+    let scenarios = scenarios
+        .into_iter()
+        .map(|scenario| {
+            let mut simulated = run.clone();
+            recompute_run(&mut simulated, &samples, &scenario.overrides.apply(&settings), &intensity_map);
+            ScenarioResult {
+                name: scenario.name,
+                figures: RunFigures::of(&simulated),
+                energy: simulated.energy,
+                carbon: simulated.carbon,
+                cost: simulated.cost,
+                water: simulated.water,
+            }
+        })
+        .collect();
+
+    Ok(SimulationReport {
+        run_id,
+        baseline: RunFigures::of(&run),
+        scenarios,
+    })
 }
 
 #[tauri::command]
-fn save_settings(app_handle: tauri::AppHandle, settings: AppSettings) -> Result<serde_json::Value, String> {
-    save_settings_to_disk(&app_handle, &settings);
-    Ok(serde_json::json!({ "success": true }))
+fn get_storage_issues(issues: State<Arc<StorageIssues>>) -> Result<Vec<StorageIssue>, CarbonLintError> {
+    Ok(issues.issues.lock()?.clone())
 }
 
 #[tauri::command]
-fn get_carbon_intensity_data() -> HashMap<String, CarbonIntensityEntry> {
-    get_carbon_intensity_map()
+fn get_settings(app_handle: tauri::AppHandle) -> Result<AppSettings, CarbonLintError> {
+    current_settings(&app_handle)
 }
 
 #[tauri::command]
-fn get_hardware_profiles_data() -> HashMap<String, HardwareProfileEntry> {
-    get_hardware_profiles_map()
+fn save_settings(app_handle: tauri::AppHandle, settings: AppSettings) -> Result<serde_json::Value, CarbonLintError> {
+    validate_settings(&settings)?;
+    save_settings_to_disk(&app_handle, &settings)?;
+    Ok(serde_json::json!({ "success": true }))
 }
 
-// ============================================
-// Background Workers
-// ============================================
+/// Checks settings from the UI before they are saved.
+fn validate_settings(settings: &AppSettings) -> Result<(), CarbonLintError> {
+    if let Some(prices) = settings.tariff.as_ref().and_then(|t| t.time_of_use.clone()) {
+        validate_intensity_profile(&mut IntensityProfile::TypicalDay { hours: prices, utc_offset_minutes: 0 })
+            .map_err(CarbonLintError::InvalidSettings)?;
+    }
+    if let Some(generation) = &settings.onsite_generation {
+        let mut profile = IntensityProfile::TypicalDay {
+            hours: generation.hourly_share.clone(),
+            utc_offset_minutes: generation.utc_offset_minutes,
+        };
+        validate_intensity_profile(&mut profile).map_err(CarbonLintError::InvalidSettings)?;
+        if generation.hourly_share.iter().flatten().any(|s| !(0.0..=1.0).contains(s)) {
+            return Err(CarbonLintError::InvalidSettings("On-site generation shares must be between 0 and 1".to_string()));
+        }
+    }
+    if settings.process_watch.rules.iter().any(|rule| rule.pattern.trim().is_empty()) {
+        return Err(CarbonLintError::InvalidSettings("Process watch rules need a process name".to_string()));
+    }
+    if !(0.0..).contains(&settings.shell_integration.min_duration_seconds) {
+        return Err(CarbonLintError::InvalidSettings("Minimum command duration cannot be negative".to_string()));
+    }
+    if settings.sample_retention.session_sample_cap < MIN_SESSION_SAMPLE_CAP {
+        return Err(CarbonLintError::InvalidSettings(format!(
+            "Sessions must keep at least {} samples in memory",
+            MIN_SESSION_SAMPLE_CAP
+        )));
+    }
+    Ok(())
+}
 
-fn spawn_slow_metrics_worker(slow_metrics: Arc<Mutex<SlowMetrics>>) {
-    thread::spawn(move || {
-        let mut networks = Networks::new_with_refreshed_list();
-        let mut sys = System::new();
-
-        let mut last_update = Instant::now();
-        let mut last_rx = 0;
-        let mut last_tx = 0;
-
-        loop {
-            thread::sleep(Duration::from_secs(2)); 
-            let now = Instant::now();
-            let elapsed = now.duration_since(last_update).as_secs_f64();
-            if elapsed == 0.0 { continue; } 
-
-            // --- Network ---
-            networks.refresh(); 
-            let mut current_rx = 0;
-            let mut current_tx = 0;
-            for (_name, data) in &networks {
-                 current_rx += data.received();
-                 current_tx += data.transmitted();
-            }
+#[tauri::command]
+fn get_carbon_intensity_data(app_handle: tauri::AppHandle) -> Result<HashMap<String, CarbonIntensityEntry>, CarbonLintError> {
+    Ok(load_intensity_map(&app_handle))
+}
 
-            // Calculate rate (bytes/sec)
-            let rx_delta = if current_rx >= last_rx { current_rx - last_rx } else { 0 };
-            let tx_delta = if current_tx >= last_tx { current_tx - last_tx } else { 0 };
-
-            let rx_rate = rx_delta as f64 / elapsed;
-            let tx_rate = tx_delta as f64 / elapsed;
-
-            // --- Disk ---
-            sys.refresh_processes_specifics(ProcessRefreshKind::new().with_disk_usage());
-            let mut current_read = 0;
-            let mut current_write = 0;
-            for (_pid, process) in sys.processes() {
-                let usage = process.disk_usage();
-                current_read += usage.read_bytes;
-                current_write += usage.written_bytes;
-            }
-            
-            // Disk stats in sysinfo 0.30 via processes are usually delta since last refresh?
-            // "Returns the bytes read/written since the last refresh." - Docs
-            // So `current_read` is ALREADY the delta over the last 2 seconds.
-            // So rate = current_read / elapsed.
-            
-            let disk_read_rate = current_read as f64 / elapsed;
-            let disk_write_rate = current_write as f64 / elapsed;
-
-            // Update Net Stats
-            let net_stats = NetworkStats {
-                rx_mb: current_rx as f64 / 1_048_576.0,
-                tx_mb: current_tx as f64 / 1_048_576.0,
-                rx_per_sec: rx_rate,
-                tx_per_sec: tx_rate,
-            };
-
-            // --- Disk ---
-            let disk_stats = DiskStats {
-                read_mb: current_read as f64 / 1_048_576.0,
-                write_mb: current_write as f64 / 1_048_576.0,
-                read_per_sec: disk_read_rate,
-                write_per_sec: disk_write_rate,
-            };
-
-            // Update cache
-            {
-                let mut guard = slow_metrics.lock().unwrap();
-                guard.network = net_stats;
-                guard.disk = disk_stats;
-            }
+#[tauri::command]
+async fn get_current_intensity(app_handle: tauri::AppHandle) -> Result<IntensityReading, CarbonLintError> {
+    run_blocking(move || {
+        let settings = current_settings(&app_handle)?;
+        Ok(resolve_intensity(&app_handle, &settings))
+    })
+    .await
+}
 
-            // Update "last" values
-            last_rx = current_rx;
-            last_tx = current_tx;
-            last_update = now;
-        }
-    });
+#[tauri::command]
+fn save_intensity_profile(
+    app_handle: tauri::AppHandle,
+    region: String,
+    mut profile: IntensityProfile,
+) -> Result<serde_json::Value, CarbonLintError> {
+    validate_intensity_profile(&mut profile).map_err(CarbonLintError::InvalidInput)?;
+    let _lock = DataDirLock::acquire(&app_handle)?;
+    let mut profiles = load_intensity_profiles_from_disk(&app_handle)?;
+    profiles.insert(region, profile);
+    save_intensity_profiles_to_disk(&app_handle, &profiles)?;
+    Ok(serde_json::json!({ "success": true }))
 }
 
-// ============================================
-// Logic Implementation (Reusable)
-// ============================================
+#[tauri::command]
+fn delete_intensity_profile(app_handle: tauri::AppHandle, region: String) -> Result<serde_json::Value, CarbonLintError> {
+    let _lock = DataDirLock::acquire(&app_handle)?;
+    let mut profiles = load_intensity_profiles_from_disk(&app_handle)?;
+    if profiles.remove(&region).is_none() {
+        return Err(CarbonLintError::NotFound(format!("No intensity profile for {}", region)));
+    }
+    save_intensity_profiles_to_disk(&app_handle, &profiles)?;
+    Ok(serde_json::json!({ "success": true }))
+}
 
-fn start_profiling_impl(state: &State<AppState>) -> Result<String, String> {
-    let mut session_guard = state.profiling_session.lock().map_err(|e| e.to_string())?;
-    
-    if session_guard.is_some() {
-        return Err("Profiling session already in progress".to_string());
-    }
-
-    let now = Utc::now();
-    let session = ProfilingSession {
-        id: format!("cl_{}", now.timestamp_millis()),
-        start_time: now.timestamp_millis(),
-        start_timestamp: now.to_rfc3339(),
-        command: "Global Shortcut".to_string(),
-        project: "CarbonLint Dashboard".to_string(),
-        branch: "main".to_string(),
-        commit: "N/A".to_string(),
-        samples: Vec::new(),
-    };
+#[tauri::command]
+fn get_hardware_profiles_data() -> HashMap<String, HardwareProfileEntry> {
+    get_hardware_profiles_map()
+}
 
-    *session_guard = Some(session);
-    Ok("Profiling started via shortcut".to_string())
-}
-
-fn stop_profiling_impl(app_handle: &tauri::AppHandle, state: &State<AppState>) -> Result<RunResult, String> {
-    let mut session_guard = state.profiling_session.lock().map_err(|e| e.to_string())?;
-    
-    if let Some(session) = session_guard.take() {
-        let duration_ms = Utc::now().timestamp_millis() - session.start_time;
-        #[allow(unused_variables)]
-        let duration_seconds = duration_ms as f64 / 1000.0;
-
-        // Calculate averages
-        let sample_count = session.samples.len();
-        let avg_cpu = if sample_count > 0 {
-            session.samples.iter().map(|s| s.cpu_utilization).sum::<f32>() / sample_count as f32
-        } else { 0.0 };
-        
-        // ... (Logic identical to previous stop_profiling) ...
-        let max_memory = if sample_count > 0 {
-             session.samples.iter().map(|s| s.memory_used).max().unwrap_or(0)
-        } else { 0 };
-
-        let avg_mem_percent = if sample_count > 0 {
-            session.samples.iter().map(|s| s.memory_percent).sum::<f32>() / sample_count as f32
-        } else { 0.0 };
-
-        let avg_disk_read = if sample_count > 0 {
-            session.samples.iter().map(|s| s.disk_read_per_sec).sum::<f64>() / sample_count as f64
-        } else { 0.0 };
-        let avg_disk_write = if sample_count > 0 {
-            session.samples.iter().map(|s| s.disk_write_per_sec).sum::<f64>() / sample_count as f64
-        } else { 0.0 };
-        
-        let avg_net_rx = if sample_count > 0 {
-            session.samples.iter().map(|s| s.net_rx_per_sec).sum::<f64>() / sample_count as f64
-        } else { 0.0 };
-        let avg_net_tx = if sample_count > 0 {
-            session.samples.iter().map(|s| s.net_tx_per_sec).sum::<f64>() / sample_count as f64
-        } else { 0.0 };
-
-        let total_disk_read_mb = (avg_disk_read * duration_seconds) / 1_048_576.0;
-        let total_disk_write_mb = (avg_disk_write * duration_seconds) / 1_048_576.0;
-        let total_net_recv_mb = (avg_net_rx * duration_seconds) / 1_048_576.0;
-        let total_net_sent_mb = (avg_net_tx * duration_seconds) / 1_048_576.0;
-
-        let disk_activity_percent = ((avg_disk_read + avg_disk_write) / 100_000_000.0 * 100.0).min(100.0) as f32;
-        let net_activity_percent = ((avg_net_rx + avg_net_tx) / 10_000_000.0 * 100.0).min(100.0) as f32;
-
-        let metrics = RunMetrics {
-            cpuUtilization: avg_cpu,
-            memoryUsagePercent: avg_mem_percent,
-            gpuUtilization: 0.0,
-            diskActivity: disk_activity_percent,
-            networkActivity: net_activity_percent,
-        };
+/// The run a deep link asked to show, if any; cleared once taken.
+#[tauri::command]
+fn take_deep_link_run(state: State<AppState>) -> Result<Option<String>, CarbonLintError> {
+    Ok(state.deep_link_run.lock()?.take())
+}
 
-        let settings = load_settings_from_disk(app_handle);
-        let energy = calculate_energy(&metrics, duration_seconds, &settings.hardware_profile);
-        let carbon = calculate_carbon(energy.total_kwh, &settings.region, settings.pue);
-
-        let resources = RunResources {
-            wall_time: duration_seconds,
-            cpu_utilization: avg_cpu,
-            cpu_time_user: (avg_cpu as f64 / 100.0) * duration_seconds * 0.7,
-            cpu_time_system: (avg_cpu as f64 / 100.0) * duration_seconds * 0.3,
-            memory_peak_mb: max_memory,
-            memory_avg_percent: avg_mem_percent,
-            disk_read_mb: total_disk_read_mb,
-            disk_write_mb: total_disk_write_mb,
-            net_recv_mb: total_net_recv_mb,
-            net_sent_mb: total_net_sent_mb,
-            gpu_utilization: 0.0,
-        };
+/// The question for a start or stop link waiting to be allowed, if any.
+#[tauri::command]
+fn get_deep_link_prompt(state: State<AppState>) -> Result<Option<String>, CarbonLintError> {
+    Ok(state.deep_link_pending.lock()?.as_ref().map(|pending| pending.prompt.clone()))
+}
 
-        let result = RunResult {
-            id: session.id,
-            project: session.project,
-            command: session.command,
-            branch: session.branch,
-            commit: session.commit,
-            timestamp: session.start_timestamp,
-            resources,
-            metrics,
-            carbon,
-            energy,
-            sampleCount: sample_count,
-            durationMs: duration_ms,
-        };
-        
-        save_run_to_disk(&app_handle, &result);
-        Ok(result)
-    } else {
-        Err("No profiling session".to_string())
+/// Runs the waiting link if `accept`, otherwise drops it.
+#[tauri::command]
+async fn confirm_deep_link(app_handle: tauri::AppHandle, accept: bool) -> Result<(), CarbonLintError> {
+    run_blocking(move || {
+        let pending = app_handle.state::<AppState>().deep_link_pending.lock()?.take();
+        if let Some(pending) = pending.filter(|_| accept) {
+            pending.run(&app_handle);
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// A hook script for `bash`, `zsh` or `fish` that reports each command to
+/// the running dashboard.
+#[tauri::command]
+fn get_shell_hook(app_handle: tauri::AppHandle, shell: String) -> Result<String, CarbonLintError> {
+    if cfg!(not(unix)) {
+        return Err(CarbonLintError::InvalidInput("Shell integration needs Unix domain sockets".to_string()));
     }
+    let template = match shell.as_str() {
+        "bash" => SHELL_HOOK_BASH,
+        "zsh" => SHELL_HOOK_ZSH,
+        "fish" => SHELL_HOOK_FISH,
+        _ => return Err(CarbonLintError::InvalidInput(format!("Unsupported shell '{}'", shell))),
+    };
+    let socket = get_ipc_socket_path(&app_handle)?;
+    Ok(template.replace("__SOCKET__", &shell_quote(&shell, &socket.to_string_lossy())))
 }
 
 #[cfg(desktop)]
@@ -864,6 +2324,7 @@ use tauri_plugin_autostart::MacosLauncher;
 use tauri_plugin_global_shortcut::{Code, Modifiers, ShortcutState};
 
 use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_deep_link::DeepLinkExt;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
@@ -873,12 +2334,25 @@ pub fn run() {
     let app_state = AppState {
         sys: sys.clone(),
         slow_metrics: slow_metrics.clone(),
-        profiling_session: Mutex::new(None),
+        profiling_sessions: Mutex::new(HashMap::new()),
+        deep_link_run: Mutex::new(None),
+        deep_link_pending: Mutex::new(None),
+        shell_queues: Arc::new(ShellQueues::default()),
     };
 
     spawn_slow_metrics_worker(slow_metrics);
 
-    let mut builder = tauri::Builder::default()
+    let mut builder = tauri::Builder::default();
+
+    #[cfg(desktop)]
+    {
+        // Registered first so that a second launch, such as a `carbonlint://`
+        // link on Windows or Linux, hands its URL to this instance and exits.
+        builder = builder.plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| show_main_window(app)));
+    }
+
+    builder = builder
+        .plugin(tauri_plugin_deep_link::init())
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_notification::init());
 
@@ -896,26 +2370,7 @@ pub fn run() {
                         if event.state == ShortcutState::Pressed {
                             if shortcut.matches(Modifiers::CONTROL | Modifiers::SHIFT, Code::KeyP) || 
                                shortcut.matches(Modifiers::META | Modifiers::SHIFT, Code::KeyP) {
-                                 let state = app.state::<AppState>();
-                                 let is_profiling = state.profiling_session.lock().unwrap().is_some();
-                                 
-                                 if is_profiling {
-                                    if let Ok(_) = stop_profiling_impl(app.app_handle(), &state) {
-                                        let _ = app.notification()
-                                            .builder()
-                                            .title("CarbonLint")
-                                            .body("Profiling stopped & saved!")
-                                            .show();
-                                    }
-                                 } else {
-                                    if let Ok(_) = start_profiling_impl(&state) {
-                                        let _ = app.notification()
-                                            .builder()
-                                            .title("CarbonLint")
-                                            .body("Profiling started!")
-                                            .show();
-                                    }
-                                 }
+                                 toggle_profiling(app);
                             } else if shortcut.matches(Modifiers::CONTROL | Modifiers::SHIFT, Code::KeyD) ||
                                       shortcut.matches(Modifiers::META | Modifiers::SHIFT, Code::KeyD) {
                                 if let Some(window) = app.get_webview_window("main") {
@@ -934,6 +2389,53 @@ pub fn run() {
     }
 
     builder.setup(|app| {
+        let storage_issues = app.state::<Arc<StorageIssues>>().inner().clone();
+        check_data_integrity(app.handle(), &storage_issues)?;
+        // Settings problems shouldn't keep the app from starting: they are
+        // listed on the dashboard and the defaults apply until they're fixed.
+        if let Err(e) = migrate_settings_file(app.handle()) {
+            storage_issues.report("settings", None, format!("Could not upgrade the settings file: {}", e.message()));
+        }
+        let run_store = open_run_store(app.handle(), storage_issues.clone())?;
+        let retention = current_settings(app.handle())
+            .unwrap_or_else(|e| {
+                storage_issues.report("settings", None, format!("Could not read settings: {}", e.message()));
+                AppSettings::default()
+            })
+            .sample_retention;
+        let cutoff = Utc::now() - chrono::Duration::days(retention.full_resolution_days);
+        run_store.apply_sample_retention(&cutoff.to_rfc3339(), retention.downsampled_points)?;
+        app.manage(run_store);
+
+        let recovered = mark_recovered_sessions(app.handle())?;
+        if recovered > 0 {
+            notify(app.handle(), format!("{} interrupted profiling session(s) can be recovered from the dashboard.", recovered));
+        }
+        spawn_checkpoint_worker(app.handle().clone());
+        spawn_session_sampler(app.handle().clone());
+        spawn_process_watcher(app.handle().clone());
+        if let Err(e) = spawn_ipc_listener(app.handle().clone()) {
+            notify(app.handle(), format!("The control socket is unavailable: {}", e.message()));
+        }
+
+        // Installed builds register the scheme; this covers dev runs and AppImages.
+        #[cfg(any(windows, target_os = "linux"))]
+        let _ = app.deep_link().register_all();
+        let handle = app.handle().clone();
+        app.deep_link().on_open_url(move |event| {
+            for url in event.urls() {
+                handle_deep_link(&handle, &url);
+            }
+        });
+        match app.deep_link().get_current() {
+            Ok(urls) => {
+                for url in urls.unwrap_or_default() {
+                    handle_deep_link(app.handle(), &url);
+                }
+            }
+            Err(e) => notify(app.handle(), format!("Could not read the link CarbonLint was opened with: {}", e)),
+        }
+
         #[cfg(desktop)]
         {
             let icon_bytes = include_bytes!("../icons/icon.ico");
@@ -951,6 +2453,8 @@ pub fn run() {
                 .on_menu_event(|app, event| {
                     match event.id.as_ref() {
                         "quit" => {
+                            // Keep the samples since the last periodic checkpoint.
+                            let _ = checkpoint_active_sessions(app);
                             app.exit(0);
                         }
                         "toggle" => {
@@ -964,26 +2468,7 @@ pub fn run() {
                             }
                         }
                         "toggle_profiling" => {
-                             let state = app.state::<AppState>();
-                             let is_profiling = state.profiling_session.lock().unwrap().is_some();
-                             
-                             if is_profiling {
-                                if let Ok(_) = stop_profiling_impl(app.app_handle(), &state) {
-                                    let _ = app.notification()
-                                        .builder()
-                                        .title("CarbonLint")
-                                        .body("Profiling stopped & saved!")
-                                        .show();
-                                }
-                             } else {
-                                if let Ok(_) = start_profiling_impl(&state) {
-                                    let _ = app.notification()
-                                        .builder()
-                                        .title("CarbonLint")
-                                        .body("Profiling started!")
-                                        .show();
-                                }
-                             }
+                             toggle_profiling(app);
                         }
                         _ => {}
                     }
@@ -994,6 +2479,8 @@ pub fn run() {
         Ok(())
     })
     .manage(app_state)
+    .manage(Arc::new(StorageIssues::default()))
+    .manage(SettingsCache::default())
     .on_window_event(|window, event| match event {
         tauri::WindowEvent::CloseRequested { api, .. } => {
             #[cfg(desktop)]
@@ -1009,15 +2496,467 @@ pub fn run() {
             start_profiling,
             stop_profiling,
             get_profiling_status,
+            pause_profiling,
+            resume_profiling,
+            mark_phase,
+            get_recovered_sessions,
+            finalize_recovered_session,
+            discard_recovered_session,
             get_runs,
             get_run,
+            get_run_samples,
             delete_run,
             get_stats_summary,
             get_settings,
+            get_storage_issues,
             save_settings,
             get_carbon_intensity_data,
-            get_hardware_profiles_data
+            get_current_intensity,
+            suggest_run_window,
+            recompute_runs,
+            simulate_run,
+            save_intensity_profile,
+            delete_intensity_profile,
+            get_hardware_profiles_data,
+            get_shell_hook,
+            take_deep_link_run,
+            get_deep_link_prompt,
+            confirm_deep_link
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn run_at(timestamp: &str) -> RunResult {
+        serde_json::from_value(serde_json::json!({
+            "id": "run-1",
+            "project": "carbonlint",
+            "command": "cargo build",
+            "branch": "main",
+            "commit": "abc1234",
+            "timestamp": timestamp,
+            "resources": {
+                "wall_time": 60.0, "cpu_utilization": 50.0, "cpu_time_user": 0.0, "cpu_time_system": 0.0,
+                "memory_peak_mb": 0, "memory_avg_percent": 40.0, "disk_read_mb": 0.0, "disk_write_mb": 0.0,
+                "net_recv_mb": 0.0, "net_sent_mb": 0.0, "gpu_utilization": 0.0
+            },
+            "metrics": {
+                "cpuUtilization": 50.0, "memoryUsagePercent": 40.0, "gpuUtilization": 0.0,
+                "diskActivity": 0.0, "networkActivity": 0.0
+            },
+            "carbon": { "total_grams": 1.0, "region": "Mixed", "intensity": 123.0, "pue": 1.0,
+                        "time_varying": true, "average_intensity": 123.0 },
+            "energy": { "total_kwh": 0.001, "cpu_kwh": 0.001, "gpu_kwh": 0.0, "memory_kwh": 0.0,
+                        "disk_kwh": 0.0, "network_kwh": 0.0 },
+            "sampleCount": 0,
+            "durationMs": 60_000
+        }))
+        .unwrap()
+    }
+
+    fn profiled_map(gco2_kwh: f64) -> HashMap<String, CarbonIntensityEntry> {
+        let mut map = get_carbon_intensity_map();
+        let entry = map.get_mut("GLOBAL-AVG").unwrap();
+        entry.hourly_profile = Some(IntensityProfile::TypicalDay { hours: vec![vec![gco2_kwh; 24]], utc_offset_minutes: 0 });
+        map
+    }
+
+    pub(crate) fn sample(timestamp: i64) -> ResourceSample {
+        ResourceSample {
+            timestamp,
+            cpu_utilization: 50.0,
+            memory_used: 0,
+            memory_percent: 40.0,
+            disk_read_per_sec: 0.0,
+            disk_write_per_sec: 0.0,
+            net_rx_per_sec: 0.0,
+            net_tx_per_sec: 0.0,
+            count: 1,
+        }
+    }
+
+    #[test]
+    fn run_blocking_reports_a_panicking_task_as_internal() {
+        let ok = tauri::async_runtime::block_on(run_blocking(|| Ok(7)));
+        assert!(matches!(ok, Ok(7)));
+        let panicked = tauri::async_runtime::block_on(run_blocking(|| -> Result<(), CarbonLintError> { panic!("boom") }));
+        assert!(matches!(panicked, Err(CarbonLintError::Internal(_))), "got {:?}", panicked);
+    }
+
+    #[test]
+    fn watch_rule_matches_program_name_and_leading_args() {
+        let rule = |pattern: &str| WatchRule { pattern: pattern.into(), project: None, command: None };
+        let args = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+
+        assert!(rule("cargo").matches_command("cargo", &args(&["build"])));
+        assert!(rule("cargo").matches_command("Cargo.exe", &[]));
+        assert!(!rule("cargo").matches_command("cargo-watch", &[]));
+
+        let docker_build = rule("docker build");
+        assert!(docker_build.matches_command("docker", &args(&["build", "-t", "api", "."])));
+        assert!(!docker_build.matches_command("docker", &args(&["run", "build"])));
+        assert!(!docker_build.matches_command("docker", &[]));
+        assert!(!docker_build.matches_command("docker", &args(&["Build"])));
+
+        assert!(!rule("").matches_command("cargo", &[]));
+        assert!(!rule("   ").matches_command("", &[]));
+    }
+
+    #[test]
+    fn recompute_run_keeps_weighted_average_without_samples() {
+        let mut run = run_at("2024-05-01T10:00:00Z");
+        recompute_run(&mut run, &[], &AppSettings::default(), &profiled_map(400.0));
+
+        assert_eq!(run.carbon.average_intensity, 123.0);
+        assert!(run.carbon.time_varying);
+        assert_eq!(run.revisions.len(), 1);
+        assert_eq!(run.revisions[0].carbon.average_intensity, 123.0);
+    }
+
+    #[test]
+    fn recompute_run_reweights_samples_and_follows_region_changes() {
+        let start = DateTime::parse_from_rfc3339("2024-05-01T10:00:00Z").unwrap().timestamp_millis();
+        let samples: Vec<_> = (1..=30).map(|i| sample(start + i * 2000)).collect();
+
+        let mut run = run_at("2024-05-01T10:00:00Z");
+        recompute_run(&mut run, &samples, &AppSettings::default(), &profiled_map(400.0));
+        assert!((run.carbon.average_intensity - 400.0).abs() < 1e-9);
+
+        let mut run = run_at("2024-05-01T10:00:00Z");
+        let settings = AppSettings { region: "EU-NORTH".into(), ..Default::default() };
+        recompute_run(&mut run, &[], &settings, &profiled_map(400.0));
+        assert_eq!(run.carbon.average_intensity, 25.0);
+        assert!(!run.carbon.time_varying);
+    }
+
+    #[test]
+    fn recompute_run_applies_setting_overrides_and_is_stable_without_them() {
+        let start = ms("2024-05-01T10:00:00Z");
+        let samples = samples_between(start, start + 60_000, &[]);
+        let map = get_carbon_intensity_map();
+        let settings = AppSettings::default();
+
+        let mut baseline = run_at("2024-05-01T10:00:00Z");
+        recompute_run(&mut baseline, &samples, &settings, &map);
+        let mut again = baseline.clone();
+        recompute_run(&mut again, &samples, &SettingsOverrides::default().apply(&settings), &map);
+        assert_eq!(again.energy.total_kwh, baseline.energy.total_kwh);
+        assert_eq!(again.carbon.total_grams, baseline.carbon.total_grams);
+        assert_eq!(again.revisions.len(), 2);
+
+        let doubled_pue = SettingsOverrides { pue: Some(settings.pue * 2.0), ..Default::default() };
+        let mut simulated = baseline.clone();
+        recompute_run(&mut simulated, &samples, &doubled_pue.apply(&settings), &map);
+        assert_eq!(simulated.energy.total_kwh, baseline.energy.total_kwh);
+        assert!((simulated.carbon.total_grams - 2.0 * baseline.carbon.total_grams).abs() < 1e-12);
+
+        let other_region = SettingsOverrides { region: Some("EU-NORTH".into()), ..Default::default() };
+        let mut simulated = baseline.clone();
+        recompute_run(&mut simulated, &samples, &other_region.apply(&settings), &map);
+        assert_eq!(simulated.carbon.average_intensity, 25.0);
+        assert!((simulated.carbon.total_grams - simulated.energy.total_kwh * settings.pue * 25.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn market_based_figures_use_the_supplier_factor_on_grid_energy_only() {
+        let start = ms("2024-05-01T10:00:00Z");
+        let samples = samples_between(start, start + 60_000, &[]);
+        let active = [(start, start + 60_000)];
+        let map = get_carbon_intensity_map();
+        let entry = map.get("GLOBAL-AVG");
+        let plain = AppSettings { pue: 1.5, ..Default::default() };
+        let energy = calculate_energy(&metrics_from_samples(&samples), 60.0, &plain.hardware_profile, plain.energy_model);
+
+        // Without a contract or generation, both methods agree.
+        let carbon = calculate_carbon_timed(&energy, &samples, &active, entry, &plain);
+        assert_eq!(carbon.onsite_kwh, 0.0);
+        assert_eq!(carbon.market_intensity, carbon.average_intensity);
+        assert!((carbon.market_grams - carbon.location_grams).abs() < 1e-12);
+
+        let settings = AppSettings {
+            supplier_emission_factor: Some(50.0),
+            onsite_generation: Some(OnsiteGeneration { hourly_share: vec![vec![0.25; 24]], utc_offset_minutes: 0 }),
+            ..plain.clone()
+        };
+        let carbon = calculate_carbon_timed(&energy, &samples, &active, entry, &settings);
+        let facility_kwh = energy.total_kwh * 1.5;
+        assert!((carbon.onsite_kwh - facility_kwh * 0.25).abs() < 1e-15);
+        assert_eq!(carbon.market_intensity, 50.0);
+        assert!((carbon.market_grams - facility_kwh * 0.75 * 50.0).abs() < 1e-12);
+        assert!((carbon.location_grams - facility_kwh * 0.75 * carbon.average_intensity).abs() < 1e-12);
+        assert_eq!(carbon.location_grams, carbon.average_grams);
+
+        // Shares outside 0-1 are clamped rather than producing negative grid energy.
+        let settings = AppSettings {
+            onsite_generation: Some(OnsiteGeneration { hourly_share: vec![vec![1.5; 24]], utc_offset_minutes: 0 }),
+            ..plain
+        };
+        let carbon = calculate_carbon_timed(&energy, &samples, &active, entry, &settings);
+        assert!((carbon.onsite_kwh - facility_kwh).abs() < 1e-15);
+        assert!(carbon.market_grams.abs() < 1e-12);
+    }
+
+    fn ms(rfc3339: &str) -> i64 {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().timestamp_millis()
+    }
+
+    fn series(points: &[(i64, f64)]) -> IntensityProfile {
+        let points = points
+            .iter()
+            .map(|&(t, gco2_kwh)| IntensityPoint { timestamp: DateTime::from_timestamp_millis(t).unwrap(), gco2_kwh })
+            .collect();
+        IntensityProfile::TimeSeries { points, interval_minutes: 60 }
+    }
+
+    /// Samples every 2 s over `[start, end]`, none while paused, as the
+    /// sampler would take them.
+    fn samples_between(start: i64, end: i64, excluded: &[PauseInterval]) -> Vec<ResourceSample> {
+        (1..)
+            .map(|i| start + i * 2000)
+            .take_while(|&t| t <= end)
+            .filter(|&t| !excluded.iter().any(|p| t > p.start && t <= p.end))
+            .map(sample)
+            .collect()
+    }
+
+    #[test]
+    fn phase_spans_add_an_initial_phase_before_the_first_marker() {
+        let marker = |name: &str, timestamp| PhaseMarker { name: name.into(), timestamp };
+        assert!(phase_spans(&[], 0, 100).is_empty());
+        assert_eq!(
+            phase_spans(&[marker("build", 10), marker("test", 60)], 0, 100),
+            vec![("initial".to_string(), 0, 10), ("build".to_string(), 10, 60), ("test".to_string(), 60, 100)]
+        );
+        assert_eq!(phase_spans(&[marker("build", 0)], 0, 100), vec![("build".to_string(), 0, 100)]);
+    }
+
+    #[test]
+    fn phase_results_cover_empty_and_fully_paused_phases() {
+        let start = ms("2024-05-01T10:00:00Z");
+        let end = start + 60_000;
+        let excluded = [PauseInterval { start: start + 20_000, end: start + 40_000 }];
+        let samples = samples_between(start, end, &excluded);
+        let settings = AppSettings::default();
+        let marker = |name: &str, timestamp| PhaseMarker { name: name.into(), timestamp };
+
+        // Two markers at once leave a zero-length phase; one inside the pause
+        // gets no active time; the last runs to the end.
+        let markers = [marker("a", start), marker("b", start), marker("paused", start + 20_000), marker("c", start + 40_000)];
+        let phases: Vec<PhaseResult> = phase_spans(&markers, start, end)
+            .into_iter()
+            .map(|(name, from, to)| phase_result(name, from, to, &samples, &excluded, None, &settings))
+            .collect();
+        let names: Vec<&str> = phases.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "paused", "c"]);
+
+        for idle in [&phases[0], &phases[2]] {
+            assert_eq!((idle.duration_ms, idle.sample_count), (0, 0), "{}", idle.name);
+            assert_eq!((idle.energy_kwh, idle.carbon_grams), (0.0, 0.0), "{}", idle.name);
+        }
+        assert_eq!((phases[1].duration_ms, phases[3].duration_ms), (20_000, 20_000));
+        assert!(phases[1].energy_kwh > 0.0 && phases[3].energy_kwh > 0.0);
+    }
+
+    #[test]
+    fn active_spans_cut_out_excluded_time() {
+        let excluded = [PauseInterval { start: 60, end: 80 }, PauseInterval { start: 10, end: 20 }];
+        assert_eq!(active_spans(&excluded, 0, 100), vec![(0, 10), (20, 60), (80, 100)]);
+        assert_eq!(active_spans(&excluded, 15, 70), vec![(20, 60)]);
+        assert!(active_spans(&excluded, 62, 78).is_empty());
+    }
+
+    #[test]
+    fn profile_weighted_intensity_ignores_paused_time() {
+        let start = ms("2024-05-01T10:00:00Z");
+        let end = start + 120_000;
+        // The pause covers exactly the high-intensity stretch.
+        let profile = series(&[(start, 300.0), (start + 60_000, 900.0), (start + 100_000, 300.0)]);
+        let excluded = [PauseInterval { start: start + 60_000, end: start + 100_000 }];
+        let samples = samples_between(start, end, &excluded);
+
+        let active = active_spans(&excluded, start, end);
+        let intensity = profile_weighted_intensity(&profile, 0.0, &samples, &active, &AppSettings::default()).unwrap();
+        assert!((intensity - 300.0).abs() < 1e-9, "got {}", intensity);
+    }
+
+    #[test]
+    fn phase_totals_add_up_to_the_run_total() {
+        let start = ms("2024-05-01T10:00:00Z");
+        let end = start + 120_000;
+        let excluded = [PauseInterval { start: start + 50_000, end: start + 70_000 }];
+        let samples = samples_between(start, end, &excluded);
+        let mut map = get_carbon_intensity_map();
+        let entry = map.get_mut("GLOBAL-AVG").unwrap();
+        entry.hourly_profile = Some(series(&[(start, 300.0), (start + 90_000, 500.0)]));
+        let entry = map.get("GLOBAL-AVG");
+        let settings = AppSettings::default();
+
+        let active_ms = end - start - paused_ms_between(&excluded, start, end);
+        let run = derive_impact(
+            &metrics_from_samples(&samples),
+            active_ms as f64 / 1000.0,
+            &samples,
+            &active_spans(&excluded, start, end),
+            entry,
+            &settings,
+        );
+        // 70 s of active time at 300 and 30 s at 500.
+        assert!((run.carbon.average_intensity - 360.0).abs() < 1e-9);
+
+        // The first marker comes after the start, so the unmarked stretch becomes
+        // the "initial" phase and nothing is left over.
+        let markers = [
+            PhaseMarker { name: "build".into(), timestamp: start + 40_000 },
+            PhaseMarker { name: "test".into(), timestamp: start + 100_000 },
+        ];
+        let phases: Vec<PhaseResult> = phase_spans(&markers, start, end)
+            .into_iter()
+            .map(|(name, from, to)| phase_result(name, from, to, &samples, &excluded, entry, &settings))
+            .collect();
+        assert_eq!(phases.len(), 3);
+
+        let remainder_ms = active_ms - phases.iter().map(|p| p.duration_ms).sum::<i64>();
+        let remainder_kwh = run.energy.total_kwh - phases.iter().map(|p| p.energy_kwh).sum::<f64>();
+        let remainder_grams = run.carbon.total_grams - phases.iter().map(|p| p.carbon_grams).sum::<f64>();
+        assert_eq!(remainder_ms, 0);
+        assert!(remainder_kwh.abs() < 1e-12, "energy off by {}", remainder_kwh);
+        assert!(remainder_grams.abs() < 1e-9, "carbon off by {}", remainder_grams);
+    }
+
+    #[test]
+    fn calculate_cost_weights_time_of_use_prices_by_when_energy_was_drawn() {
+        let start = ms("2024-05-01T11:59:00Z");
+        let end = start + 120_000;
+        let samples = samples_between(start, end, &[]);
+        let settings = AppSettings::default();
+        let mut hours = vec![0.10; 12];
+        hours.extend([0.30; 12]);
+        let flat = Tariff { currency: "EUR".into(), price_per_kwh: 0.25, time_of_use: None, utc_offset_minutes: 0 };
+
+        let cost = calculate_cost(2.0, &flat, &samples, &[(start, end)], &settings);
+        assert_eq!((cost.price_per_kwh, cost.total_cost, cost.time_of_use), (0.25, 0.5, false));
+
+        // Half the run falls before noon and half after.
+        let tou = Tariff { time_of_use: Some(vec![hours.clone()]), ..flat.clone() };
+        let cost = calculate_cost(2.0, &tou, &samples, &[(start, end)], &settings);
+        assert!(cost.time_of_use);
+        assert!((cost.price_per_kwh - 0.20).abs() < 1e-9, "got {}", cost.price_per_kwh);
+        assert!((cost.total_cost - 0.40).abs() < 1e-9);
+
+        // Local noon two hours earlier puts the whole run in the expensive half.
+        let shifted = Tariff { time_of_use: Some(vec![hours]), utc_offset_minutes: 120, ..flat.clone() };
+        let cost = calculate_cost(2.0, &shifted, &samples, &[(start, end)], &settings);
+        assert!((cost.price_per_kwh - 0.30).abs() < 1e-9);
+
+        // Nothing active to weight, so the flat price applies.
+        let cost = calculate_cost(2.0, &tou, &[], &[], &settings);
+        assert_eq!((cost.price_per_kwh, cost.time_of_use), (0.25, false));
+    }
+
+    fn buffer_of(cap: usize, cpu: impl IntoIterator<Item = f32>) -> SampleBuffer {
+        let mut buffer = SampleBuffer::with_cap(cap);
+        for (i, cpu) in cpu.into_iter().enumerate() {
+            buffer.push(ResourceSample { cpu_utilization: cpu, memory_used: i as u64, ..sample(i as i64 * 2000) });
+        }
+        buffer
+    }
+
+    #[test]
+    fn sample_buffer_compacts_the_older_half_past_its_cap() {
+        let buffer = buffer_of(8, [10.0, 30.0, 20.0, 40.0, 50.0, 50.0, 50.0, 50.0, 50.0]);
+
+        let counts: Vec<u32> = buffer.entries.iter().map(|e| e.sample.count).collect();
+        assert_eq!(counts, vec![2, 2, 1, 1, 1, 1, 1]);
+        let first = &buffer.entries[0];
+        assert_eq!(first.sample.timestamp, 1000);
+        assert_eq!(first.sample.cpu_utilization, 20.0);
+        assert_eq!((first.cpu_min, first.cpu_max, first.memory_max), (10.0, 30.0, 1));
+        assert_eq!(buffer.entries[2].sample.timestamp, 8000);
+    }
+
+    #[test]
+    fn compacted_samples_weigh_by_count_in_phases_and_recomputes() {
+        let start = ms("2024-05-01T10:00:00Z");
+        let end = start + 120_000;
+        let mut buffer = SampleBuffer::with_cap(8);
+        for i in 1..=60 {
+            let cpu = if i <= 10 { 90.0 } else { (i % 7) as f32 * 10.0 };
+            buffer.push(ResourceSample { cpu_utilization: cpu, ..sample(start + i * 2000) });
+        }
+        assert!(buffer.entries.len() <= 8 && buffer.entries[0].sample.count > 1);
+        let samples = buffer.to_samples();
+        let settings = AppSettings::default();
+        let map = get_carbon_intensity_map();
+        let entry = map.get(&settings.region);
+
+        // Markers at the last raw sample of a merged entry, so no entry spans two phases.
+        let mut boundaries = Vec::new();
+        let mut covered = 0;
+        for entry in &buffer.entries[..buffer.entries.len() - 1] {
+            covered += entry.sample.count as i64;
+            boundaries.push(start + covered * 2000);
+        }
+        let markers: Vec<PhaseMarker> = [start, boundaries[0], boundaries[boundaries.len() / 2]]
+            .iter()
+            .enumerate()
+            .map(|(i, &timestamp)| PhaseMarker { name: format!("p{}", i), timestamp })
+            .collect();
+        let phases: Vec<PhaseResult> = phase_spans(&markers, start, end)
+            .into_iter()
+            .map(|(name, from, to)| phase_result(name, from, to, &samples, &[], entry, &settings))
+            .collect();
+        let total = derive_impact(&buffer.metrics(), 120.0, &samples, &[(start, end)], entry, &settings);
+        assert_eq!(phases.iter().map(|p| p.sample_count).sum::<usize>(), 60);
+        let energy: f64 = phases.iter().map(|p| p.energy_kwh).sum();
+        let carbon: f64 = phases.iter().map(|p| p.carbon_grams).sum();
+        assert!((energy - total.energy.total_kwh).abs() < 1e-6 * total.energy.total_kwh);
+        assert!((carbon - total.carbon.total_grams).abs() < 1e-6 * total.carbon.total_grams);
+
+        let mut run = run_at("2024-05-01T10:00:00Z");
+        run.durationMs = 120_000;
+        run.metrics = buffer.metrics();
+        run.energy = total.energy.clone();
+        run.carbon = total.carbon.clone();
+        recompute_run(&mut run, &samples, &settings, &map);
+        assert!((run.energy.total_kwh - total.energy.total_kwh).abs() < 1e-6 * total.energy.total_kwh);
+        assert!((run.carbon.total_grams - total.carbon.total_grams).abs() < 1e-6 * total.carbon.total_grams);
+    }
+
+    #[test]
+    fn session_sample_caps_below_the_minimum_are_rejected_or_raised() {
+        let with_cap = |session_sample_cap| AppSettings {
+            sample_retention: SampleRetention { session_sample_cap, ..Default::default() },
+            ..Default::default()
+        };
+        assert!(matches!(validate_settings(&with_cap(3)), Err(CarbonLintError::InvalidSettings(_))));
+        assert!(validate_settings(&with_cap(MIN_SESSION_SAMPLE_CAP)).is_ok());
+
+        // A cap that got past validation still bounds the buffer.
+        let buffer = buffer_of(0, (0..100).map(|i| i as f32));
+        assert!(buffer.entries.len() <= MIN_SESSION_SAMPLE_CAP);
+        assert_eq!(buffer.len(), 100);
+    }
+
+    #[test]
+    fn sample_buffer_merges_by_count_and_keeps_exact_totals() {
+        let buffer = buffer_of(4, (0..20).map(|i| i as f32));
+
+        assert!(buffer.entries.len() <= 4);
+        assert_eq!(buffer.len(), 20);
+        assert_eq!(buffer.entries.iter().map(|e| e.sample.count as usize).sum::<usize>(), 20);
+        assert_eq!(buffer.metrics().cpuUtilization, 9.5);
+        assert_eq!(buffer.totals.memory_max, 19);
+
+        // Each entry sits at the mean time of the samples it covers.
+        let mut covered = 0;
+        for entry in &buffer.entries {
+            let times = (covered..covered + entry.sample.count as i64).map(|i| i * 2000);
+            assert_eq!(entry.sample.timestamp, times.sum::<i64>() / entry.sample.count as i64);
+            covered += entry.sample.count as i64;
+        }
+    }
+}
@@ -0,0 +1,919 @@
+//! Active profiling sessions: sampling, stop conditions, checkpoints and
+//! the start and stop logic shared by every entry point.
+
+use super::*;
+
+// ============================================
+// Session Checkpoints
+// ============================================
+
+/// How often an active session, samples included, is written to disk.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An active session as last written to `sessions/<id>.json`. Checkpoints
+/// found at startup are renamed to `<id>.recovered.json` until the user
+/// finalizes or discards them.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SessionCheckpoint {
+    pub(crate) session: ProfilingSession,
+    pub(crate) checkpointed_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RecoveredSession {
+    pub(crate) id: String,
+    pub(crate) project: String,
+    pub(crate) command: String,
+    pub(crate) branch: String,
+    pub(crate) commit: String,
+    pub(crate) start_time: String,
+    pub(crate) checkpointed_at: String,
+    pub(crate) sample_count: usize,
+}
+
+pub(crate) fn get_sessions_dir(app_handle: &tauri::AppHandle) -> Result<PathBuf, CarbonLintError> {
+    let dir = app_data_file(app_handle, "sessions")?;
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn session_checkpoint_path(app_handle: &tauri::AppHandle, id: &str) -> Result<PathBuf, CarbonLintError> {
+    Ok(get_sessions_dir(app_handle)?.join(format!("{}.json", id)))
+}
+
+pub(crate) fn recovered_session_path(app_handle: &tauri::AppHandle, id: &str) -> Result<PathBuf, CarbonLintError> {
+    // Ids come from the frontend; keep them inside the sessions directory.
+    if id.is_empty() || id.contains(['/', '\\', '.']) {
+        return Err(CarbonLintError::InvalidInput(format!("Invalid session id {}", id)));
+    }
+    Ok(get_sessions_dir(app_handle)?.join(format!("{}.recovered.json", id)))
+}
+
+fn write_session_checkpoint(app_handle: &tauri::AppHandle, session: &ProfilingSession) -> Result<(), CarbonLintError> {
+    let checkpoint = SessionCheckpoint {
+        session: session.clone(),
+        checkpointed_at: Utc::now().timestamp_millis(),
+    };
+    write_atomic(
+        &session_checkpoint_path(app_handle, &session.id)?,
+        &serde_json::to_vec(&checkpoint)?,
+    )
+}
+
+pub(crate) fn load_recovered_session(app_handle: &tauri::AppHandle, id: &str) -> Result<SessionCheckpoint, CarbonLintError> {
+    let path = recovered_session_path(app_handle, id)?;
+    if !path.exists() {
+        return Err(CarbonLintError::NotFound(format!("No recovered session {}", id)));
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Marks checkpoints left by a previous run of the app as recovered and
+/// returns how many there are in total.
+pub(crate) fn mark_recovered_sessions(app_handle: &tauri::AppHandle) -> Result<usize, CarbonLintError> {
+    let dir = get_sessions_dir(app_handle)?;
+    let mut count = 0;
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        if let Some(id) = name.strip_suffix(".json").filter(|id| !id.ends_with(".recovered")) {
+            fs::rename(&path, dir.join(format!("{}.recovered.json", id)))?;
+            count += 1;
+        } else if name.ends_with(".recovered.json") {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Checkpoints every active session. The sessions are copied under the
+/// lock and written after it is released, so sampling and commands don't
+/// wait on the disk. A session stopped while its checkpoint was being
+/// written has the file removed again, so it can't resurface as recovered.
+pub(crate) fn checkpoint_active_sessions(app_handle: &tauri::AppHandle) -> Result<(), CarbonLintError> {
+    let state = app_handle.state::<AppState>();
+    let snapshot: Vec<ProfilingSession> = state.profiling_sessions.lock()?.values().cloned().collect();
+    let mut written = Ok(());
+    for session in &snapshot {
+        if let Err(e) = write_session_checkpoint(app_handle, session) {
+            written = written.and(Err(e));
+        }
+    }
+
+    // A session missing now was stopped after the copy was taken, and its
+    // stop may already have removed the file just written back.
+    let stopped: Vec<&str> = {
+        let sessions = state.profiling_sessions.lock()?;
+        snapshot
+            .iter()
+            .filter(|s| !sessions.values().any(|active| active.id == s.id))
+            .map(|s| s.id.as_str())
+            .collect()
+    };
+    for id in stopped {
+        let _ = fs::remove_file(session_checkpoint_path(app_handle, id)?);
+    }
+    written
+}
+
+pub(crate) fn spawn_checkpoint_worker(app_handle: tauri::AppHandle) {
+    thread::spawn(move || loop {
+        thread::sleep(CHECKPOINT_INTERVAL);
+        if let Err(e) = checkpoint_active_sessions(&app_handle) {
+            if let Some(issues) = app_handle.try_state::<Arc<StorageIssues>>() {
+                issues.report("sessions", None, e.to_string());
+            }
+        }
+    });
+}
+
+// ============================================
+// Background Workers
+// ============================================
+
+pub(crate) fn spawn_slow_metrics_worker(slow_metrics: Arc<Mutex<SlowMetrics>>) {
+    thread::spawn(move || {
+        let mut networks = Networks::new_with_refreshed_list();
+        let mut sys = System::new();
+
+        let mut last_update = Instant::now();
+        let mut last_rx = 0;
+        let mut last_tx = 0;
+
+        loop {
+            thread::sleep(Duration::from_secs(2)); 
+            let now = Instant::now();
+            let elapsed = now.duration_since(last_update).as_secs_f64();
+            if elapsed == 0.0 { continue; } 
+
+            // --- Network ---
+            networks.refresh(); 
+            let mut current_rx = 0;
+            let mut current_tx = 0;
+            for (_name, data) in &networks {
+                 current_rx += data.received();
+                 current_tx += data.transmitted();
+            }
+
+            // Calculate rate (bytes/sec)
+            let rx_delta = if current_rx >= last_rx { current_rx - last_rx } else { 0 };
+            let tx_delta = if current_tx >= last_tx { current_tx - last_tx } else { 0 };
+
+            let rx_rate = rx_delta as f64 / elapsed;
+            let tx_rate = tx_delta as f64 / elapsed;
+
+            // --- Disk ---
+            sys.refresh_processes_specifics(ProcessRefreshKind::new().with_disk_usage());
+            let mut current_read = 0;
+            let mut current_write = 0;
+            for (_pid, process) in sys.processes() {
+                let usage = process.disk_usage();
+                current_read += usage.read_bytes;
+                current_write += usage.written_bytes;
+            }
+            
+            // Disk stats in sysinfo 0.30 via processes are usually delta since last refresh?
+            // "Returns the bytes read/written since the last refresh." - Docs
+            // So `current_read` is ALREADY the delta over the last 2 seconds.
+            // So rate = current_read / elapsed.
+            
+            let disk_read_rate = current_read as f64 / elapsed;
+            let disk_write_rate = current_write as f64 / elapsed;
+
+            // Update Net Stats
+            let net_stats = NetworkStats {
+                rx_mb: current_rx as f64 / 1_048_576.0,
+                tx_mb: current_tx as f64 / 1_048_576.0,
+                rx_per_sec: rx_rate,
+                tx_per_sec: tx_rate,
+            };
+
+            // --- Disk ---
+            let disk_stats = DiskStats {
+                read_mb: current_read as f64 / 1_048_576.0,
+                write_mb: current_write as f64 / 1_048_576.0,
+                read_per_sec: disk_read_rate,
+                write_per_sec: disk_write_rate,
+            };
+
+            // Update cache
+            {
+                if let Ok(mut guard) = slow_metrics.lock() {
+                    guard.network = net_stats;
+                    guard.disk = disk_stats;
+                }
+            }
+
+            // Update "last" values
+            last_rx = current_rx;
+            last_tx = current_tx;
+            last_update = now;
+        }
+    });
+}
+
+/// How often the session sampler records a sample and checks stop conditions.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A tick this much later than expected means time passed that the session
+/// shouldn't count.
+const GAP_THRESHOLD_MS: i64 = 10_000;
+
+/// A discontinuity seen between two sampler ticks.
+enum ClockEvent {
+    /// Exclude `[start, end)` from sessions.
+    Gap(TimeGap),
+    /// The wall clock went back; shift session timestamps by this many ms.
+    Shift(i64),
+}
+
+/// Compares the wall-clock time between ticks with a clock that stops while
+/// the machine sleeps (`awake`) and one that doesn't (`boot`). When `boot`
+/// ran well ahead of `awake` the machine was suspended. A late tick that
+/// `awake` confirms was a stall (a busy machine, a debugger), which counts
+/// as session time. A wall delta that neither clock confirms is a clock
+/// change.
+fn detect_clock_event(wall_delta_ms: i64, awake_delta_ms: i64, boot_delta_ms: i64, now_ms: i64) -> Option<ClockEvent> {
+    let expected_ms = SAMPLE_INTERVAL.as_millis() as i64;
+    if wall_delta_ms > expected_ms + GAP_THRESHOLD_MS {
+        let kind = if boot_delta_ms - awake_delta_ms > GAP_THRESHOLD_MS {
+            GapKind::Suspend
+        } else if awake_delta_ms > expected_ms + GAP_THRESHOLD_MS {
+            return None;
+        } else {
+            GapKind::ClockJump
+        };
+        // Only the expected interval was spent running.
+        return Some(ClockEvent::Gap(TimeGap {
+            start: now_ms - wall_delta_ms + expected_ms,
+            end: now_ms,
+            kind,
+        }));
+    }
+    let behind_ms = awake_delta_ms.min(expected_ms) - wall_delta_ms;
+    (behind_ms > GAP_THRESHOLD_MS / 5).then_some(ClockEvent::Shift(-behind_ms))
+}
+
+/// Milliseconds on two clocks read together: one that stops while the
+/// machine sleeps and one that keeps counting, for `detect_clock_event`.
+#[cfg(target_os = "linux")]
+fn awake_and_boot_ms() -> (i64, i64) {
+    let read = |clock| {
+        let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        // SAFETY: `ts` is a valid timespec for the call to fill in.
+        unsafe { libc::clock_gettime(clock, &mut ts) };
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32).as_millis() as i64
+    };
+    (read(libc::CLOCK_MONOTONIC), read(libc::CLOCK_BOOTTIME))
+}
+
+/// Mach absolute time stops while asleep and continuous time doesn't; both
+/// count timebase ticks. Declared here since the libc crate deprecates its
+/// Mach bindings and lacks `mach_continuous_time` (macOS 10.12+).
+#[cfg(target_os = "macos")]
+fn awake_and_boot_ms() -> (i64, i64) {
+    #[repr(C)]
+    struct MachTimebaseInfo {
+        numer: u32,
+        denom: u32,
+    }
+    extern "C" {
+        fn mach_absolute_time() -> u64;
+        fn mach_continuous_time() -> u64;
+        fn mach_timebase_info(info: *mut MachTimebaseInfo) -> i32;
+    }
+    let mut timebase = MachTimebaseInfo { numer: 0, denom: 0 };
+    // SAFETY: the clock reads take no arguments and `timebase` is valid to fill in.
+    let (awake, boot) = unsafe {
+        mach_timebase_info(&mut timebase);
+        (mach_absolute_time(), mach_continuous_time())
+    };
+    let to_ms = |ticks: u64| (ticks as u128 * timebase.numer as u128 / timebase.denom.max(1) as u128 / 1_000_000) as i64;
+    (to_ms(awake), to_ms(boot))
+}
+
+/// Unbiased interrupt time leaves out sleep and interrupt time doesn't;
+/// both count 100 ns units.
+#[cfg(windows)]
+fn awake_and_boot_ms() -> (i64, i64) {
+    use windows_sys::Win32::System::WindowsProgramming::{QueryInterruptTime, QueryUnbiasedInterruptTime};
+    let (mut awake, mut boot) = (0u64, 0u64);
+    // SAFETY: each call only writes the u64 it is given.
+    unsafe {
+        QueryUnbiasedInterruptTime(&mut awake);
+        QueryInterruptTime(&mut boot);
+    }
+    ((awake / 10_000) as i64, (boot / 10_000) as i64)
+}
+
+/// Elsewhere `Instant` and system uptime, which has whole-second resolution
+/// and may count sleep in `Instant` too, so a suspend can read as a stall.
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn awake_and_boot_ms() -> (i64, i64) {
+    static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    (START.get_or_init(Instant::now).elapsed().as_millis() as i64, System::uptime() as i64 * 1000)
+}
+
+/// Moves every timestamp in the session by `delta_ms`, keeping its timeline
+/// continuous after the wall clock was set back.
+fn shift_session_clock(session: &mut ProfilingSession, delta_ms: i64) {
+    session.start_time += delta_ms;
+    session.start_timestamp = millis_to_rfc3339(session.start_time);
+    session.paused_at = session.paused_at.map(|t| t + delta_ms);
+    for entry in session.samples.entries.iter_mut() {
+        entry.sample.timestamp += delta_ms;
+    }
+    for pause in session.pauses.iter_mut() {
+        pause.start += delta_ms;
+        pause.end += delta_ms;
+    }
+    for gap in session.gaps.iter_mut() {
+        gap.start += delta_ms;
+        gap.end += delta_ms;
+    }
+    for phase in session.phases.iter_mut() {
+        phase.timestamp += delta_ms;
+    }
+}
+
+/// `sys` must be the caller's own: CPU usage is measured since its previous
+/// refresh, so sharing it with another poller skews both readings.
+fn read_sample(sys: &mut System, slow_metrics: &Mutex<SlowMetrics>) -> Result<ResourceSample, CarbonLintError> {
+    sys.refresh_cpu();
+    sys.refresh_memory();
+    let total_mem = sys.total_memory() / 1024 / 1024;
+    let used_mem = sys.used_memory() / 1024 / 1024;
+    let slow = slow_metrics.lock()?.clone();
+    Ok(ResourceSample {
+        timestamp: Utc::now().timestamp_millis(),
+        cpu_utilization: sys.global_cpu_info().cpu_usage(),
+        memory_used: used_mem,
+        memory_percent: if total_mem > 0 { (used_mem as f32 / total_mem as f32) * 100.0 } else { 0.0 },
+        disk_read_per_sec: slow.disk.read_per_sec,
+        disk_write_per_sec: slow.disk.write_per_sec,
+        net_rx_per_sec: slow.network.rx_per_sec,
+        net_tx_per_sec: slow.network.tx_per_sec,
+        count: 1,
+    })
+}
+
+/// The first of the session's stop conditions that has been met. Energy and
+/// carbon are estimated from the samples so far with the static intensity.
+/// Sessions already being stopped, or whose automatic stop failed, are left
+/// alone.
+fn check_stop_conditions(
+    session: &ProfilingSession,
+    now: i64,
+    settings: Option<&AppSettings>,
+    process_alive: Option<bool>,
+) -> Option<StopReason> {
+    let conditions = &session.stop_conditions;
+    if session.stopping || session.stop_failed {
+        return None;
+    }
+    if process_alive == Some(false) {
+        return Some(StopReason::ProcessExited);
+    }
+    if session.paused_at.is_some() {
+        return None;
+    }
+
+    let active_ms = now - session.start_time - paused_ms_between(&session_excluded(session, now), session.start_time, now);
+    if conditions.max_duration_minutes.is_some_and(|max| active_ms as f64 >= max * 60_000.0) {
+        return Some(StopReason::MaxDuration);
+    }
+    if let Some(settings) = settings {
+        let metrics = session.samples.metrics();
+        let energy = calculate_energy(&metrics, active_ms as f64 / 1000.0, &settings.hardware_profile, settings.energy_model);
+        if conditions.max_energy_kwh.is_some_and(|max| energy.total_kwh >= max) {
+            return Some(StopReason::EnergyLimit);
+        }
+        let carbon = calculate_carbon(energy.total_kwh, &settings.region, settings.pue);
+        if conditions.max_carbon_grams.is_some_and(|max| carbon.total_grams >= max) {
+            return Some(StopReason::CarbonLimit);
+        }
+    }
+    if let Some(minutes) = conditions.idle_minutes {
+        let window_start = now - (minutes * 60_000.0) as i64;
+        let entries = &session.samples.entries;
+        let covers_window = entries.first().is_some_and(|e| e.sample.timestamp <= window_start);
+        let idle = entries
+            .iter()
+            .filter(|e| e.sample.timestamp >= window_start)
+            .all(|e| e.cpu_max < conditions.idle_cpu_percent);
+        if covers_window && idle {
+            return Some(StopReason::Idle);
+        }
+    }
+    None
+}
+
+/// Records a sample into every running session and stops the sessions whose
+/// stop conditions are met. Sampling here rather than from the dashboard's
+/// polling keeps sessions recording while the window is hidden.
+pub(crate) fn spawn_session_sampler(app_handle: tauri::AppHandle) {
+    let mut last_wall = Utc::now().timestamp_millis();
+    let (mut last_awake, mut last_boot) = awake_and_boot_ms();
+    let mut sys = System::new();
+    sys.refresh_cpu();
+    thread::spawn(move || loop {
+        thread::sleep(SAMPLE_INTERVAL);
+        let state = app_handle.state::<AppState>();
+        let Ok(sample) = read_sample(&mut sys, &state.slow_metrics) else {
+            continue;
+        };
+
+        let (now_awake, now_boot) = awake_and_boot_ms();
+        let clock_event = detect_clock_event(
+            sample.timestamp - last_wall,
+            now_awake - last_awake,
+            now_boot - last_boot,
+            sample.timestamp,
+        );
+        (last_wall, last_awake, last_boot) = (sample.timestamp, now_awake, now_boot);
+
+        // Read settings before taking the sessions lock, so commands aren't
+        // held up behind disk access.
+        let needs_settings = state.profiling_sessions.lock().is_ok_and(|sessions| {
+            sessions.values().any(|s| {
+                s.stop_conditions.max_energy_kwh.is_some() || s.stop_conditions.max_carbon_grams.is_some()
+            })
+        });
+        let settings = if needs_settings { current_settings(&app_handle).ok() } else { None };
+
+        let due: Vec<(String, String, StopReason)> = {
+            let Ok(mut sessions) = state.profiling_sessions.lock() else {
+                continue;
+            };
+            sessions
+                .values_mut()
+                .filter_map(|session| {
+                    match &clock_event {
+                        // A paused session's open pause already covers the gap.
+                        Some(ClockEvent::Gap(gap)) if session.paused_at.is_none() => {
+                            session.gaps.push(TimeGap { start: gap.start.max(session.start_time), ..gap.clone() });
+                        }
+                        Some(ClockEvent::Shift(delta_ms)) => shift_session_clock(session, *delta_ms),
+                        _ => {}
+                    }
+                    if session.paused_at.is_none() {
+                        session.samples.push(sample.clone());
+                    }
+                    let alive = session.stop_conditions.pid.map(|pid| sys.refresh_process(Pid::from_u32(pid)));
+                    check_stop_conditions(session, sample.timestamp, settings.as_ref(), alive)
+                        .map(|reason| (session.name.clone(), session.id.clone(), reason))
+                })
+                .collect()
+        };
+
+        // Saving may wait on the intensity provider, so stop off this thread
+        // to keep the other sessions sampling.
+        for (name, id, reason) in due {
+            let app_handle = app_handle.clone();
+            thread::spawn(move || {
+                let state = app_handle.state::<AppState>();
+                let body = match stop_profiling_impl(&app_handle, &state, &name, reason) {
+                    Ok(_) => format!("Profiling session '{}' stopped & saved: {}.", name, reason.describe()),
+                    Err(e) => {
+                        if let Ok(mut sessions) = state.profiling_sessions.lock() {
+                            if let Some(session) = sessions.get_mut(&name).filter(|s| s.id == id) {
+                                session.stop_failed = true;
+                            }
+                        }
+                        format!(
+                            "Profiling session '{}' could not be stopped: {}. Stop it from the dashboard to retry.",
+                            name,
+                            e.message()
+                        )
+                    }
+                };
+                notify(&app_handle, body);
+            });
+        }
+    });
+}
+
+/// Opens a session for each newly started process that matches a watch rule.
+/// The session's `pid` stop condition closes it when the process exits.
+/// Processes spawned by a watched one (rustc under cargo) are left to their
+/// ancestor's session, as are commands run from a hooked shell that already
+/// has a `shell-<pid>` session; both sessions would record the same
+/// system-wide figures. Processes already running when watching begins are
+/// not picked up part-way through.
+pub(crate) fn spawn_process_watcher(app_handle: tauri::AppHandle) {
+    let mut sys = System::new();
+    let mut known: Option<HashSet<Pid>> = None;
+    thread::spawn(move || loop {
+        thread::sleep(SAMPLE_INTERVAL);
+        let watch = match current_settings(&app_handle) {
+            Ok(settings) if settings.process_watch.enabled => settings.process_watch,
+            _ => {
+                known = None;
+                continue;
+            }
+        };
+
+        sys.refresh_processes();
+        let current: HashSet<Pid> = sys.processes().keys().copied().collect();
+        let Some(previous) = known.replace(current.clone()) else {
+            continue;
+        };
+
+        let state = app_handle.state::<AppState>();
+        let shell_pids: HashSet<u32> = state
+            .profiling_sessions
+            .lock()
+            .map(|sessions| {
+                sessions
+                    .keys()
+                    .filter_map(|name| name.strip_prefix("shell-")?.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        for pid in current.difference(&previous) {
+            let Some(process) = sys.process(*pid) else {
+                continue;
+            };
+            let Some(rule) = watch.rules.iter().find(|rule| rule.matches(process)) else {
+                continue;
+            };
+            let covered = ancestors(&sys, process).any(|ancestor| {
+                shell_pids.contains(&ancestor.pid().as_u32()) || watch.rules.iter().any(|rule| rule.matches(ancestor))
+            });
+            if covered {
+                continue;
+            }
+
+            let command = process.cmd().join(" ");
+            let session = format!("auto-{}", pid.as_u32());
+            let options = StartProfilingOptions {
+                session: Some(session.clone()),
+                stop_conditions: StopConditions { pid: Some(pid.as_u32()), ..Default::default() },
+                command: rule.command.clone().or(Some(command).filter(|c| !c.is_empty())),
+                project: rule.project.clone(),
+                cwd: process.cwd().map(|dir| dir.to_string_lossy().into_owned()),
+                trigger: Some(RunTrigger::ProcessWatch),
+                ..Default::default()
+            };
+            if let Err(e) = start_profiling_impl(&app_handle, &state, options) {
+                if let Some(issues) = app_handle.try_state::<Arc<StorageIssues>>() {
+                    issues.report("process_watch", Some(&session), e.message().to_string());
+                }
+            }
+        }
+    });
+}
+
+/// `process`'s parent, grandparent and so on. Bounded in case the process
+/// table changes mid-walk and forms a loop.
+fn ancestors<'a>(sys: &'a System, process: &sysinfo::Process) -> impl Iterator<Item = &'a sysinfo::Process> {
+    let parent_of = |process: &sysinfo::Process| process.parent().and_then(|pid| sys.process(pid));
+    std::iter::successors(parent_of(process), move |process| parent_of(process)).take(64)
+}
+
+// ============================================
+// Logic Implementation (Reusable)
+// ============================================
+
+pub(crate) fn start_profiling_impl(
+    app_handle: &tauri::AppHandle,
+    state: &State<AppState>,
+    options: StartProfilingOptions,
+) -> Result<ProfilingSession, CarbonLintError> {
+    let name = options.session.unwrap_or_else(default_session_name);
+    // Names end up in run ids and checkpoint file names.
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(CarbonLintError::InvalidInput(format!("Invalid session name '{}'", name)));
+    }
+
+    let settings = current_settings(app_handle)?;
+    let cwd = options.cwd.or(settings.project_directory).filter(|dir| !dir.trim().is_empty()).map(PathBuf::from);
+    let git = cwd.as_deref().and_then(discover_git);
+    let project = options
+        .project
+        .or_else(|| git.as_ref().map(|g| g.project.clone()))
+        .or_else(|| cwd.as_deref().and_then(Path::file_name).map(|n| n.to_string_lossy().into_owned()));
+    let branch = options.branch.or_else(|| {
+        git.as_ref().map(|g| g.branch.clone().unwrap_or_else(|| "HEAD".to_string()))
+    });
+    let commit = options.commit.or_else(|| git.as_ref().and_then(|g| g.commit.clone()));
+
+    let mut sessions = state.profiling_sessions.lock()?;
+    if sessions.contains_key(&name) {
+        return Err(CarbonLintError::SessionState(format!("Profiling session '{}' already in progress", name)));
+    }
+
+    let now = Utc::now();
+    // Ids are millisecond stamps, so a session started in the same
+    // millisecond as a live or stored run gets a numbered suffix.
+    let base_id = format!("cl_{}", now.timestamp_millis());
+    let store = app_handle.state::<RunStore>();
+    let mut id = base_id.clone();
+    for n in 2.. {
+        if !sessions.values().any(|s| s.id == id) && !store.contains(&id)? {
+            break;
+        }
+        id = format!("{}_{}", base_id, n);
+    }
+    let session = ProfilingSession {
+        id,
+        name: name.clone(),
+        start_time: now.timestamp_millis(),
+        start_timestamp: now.to_rfc3339(),
+        command: options.command.unwrap_or_else(|| "manual profiling".to_string()),
+        project: project.unwrap_or_else(|| "CarbonLint Dashboard".to_string()),
+        branch: branch.unwrap_or_else(|| "main".to_string()),
+        commit: commit.unwrap_or_else(|| "N/A".to_string()),
+        samples: SampleBuffer::with_cap(settings.sample_retention.session_sample_cap),
+        paused_at: None,
+        pauses: Vec::new(),
+        phases: Vec::new(),
+        stop_conditions: options.stop_conditions,
+        gaps: Vec::new(),
+        trigger: options.trigger,
+        repository: git.map(|g| RunRepository {
+            root: g.root.to_string_lossy().into_owned(),
+            remote_url: g.remote_url,
+            dirty: g.dirty,
+        }),
+        stopping: false,
+        stop_failed: false,
+    };
+
+    sessions.insert(name, session.clone());
+    Ok(session)
+}
+
+/// Saves the named session as a run. The session is finalized from a copy so
+/// other sessions keep sampling meanwhile, and only removed once the run is
+/// stored, so a failed save can be retried. It is marked as stopping until
+/// then, and any other stop of it is refused.
+pub(crate) fn stop_profiling_impl(
+    app_handle: &tauri::AppHandle,
+    state: &State<AppState>,
+    name: &str,
+    reason: StopReason,
+) -> Result<RunResult, CarbonLintError> {
+    let session = {
+        let mut sessions = state.profiling_sessions.lock()?;
+        let session = sessions.get_mut(name).ok_or_else(|| no_session(name))?;
+        if session.stopping {
+            return Err(session_stopping(name));
+        }
+        session.stopping = true;
+        session.clone()
+    };
+    let saved = finalize_session(app_handle, &session, Utc::now().timestamp_millis()).and_then(|mut result| {
+        result.stopReason = Some(reason);
+        save_run_to_disk(app_handle, &result, &session.samples.to_samples())?;
+        Ok(result)
+    });
+
+    let mut sessions = state.profiling_sessions.lock()?;
+    // Only touch the entry if it is still the session we stopped.
+    let current = sessions.get_mut(name).filter(|s| s.id == session.id);
+    match saved {
+        Ok(result) => {
+            if current.is_some() {
+                sessions.remove(name);
+            }
+            drop(sessions);
+            // The run is stored, so a leftover checkpoint would only resurface as a duplicate.
+            let _ = fs::remove_file(session_checkpoint_path(app_handle, &session.id)?);
+            Ok(result)
+        }
+        Err(e) => {
+            if let Some(current) = current {
+                current.stopping = false;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Ends the named session without saving a run.
+pub(crate) fn discard_session_impl(app_handle: &tauri::AppHandle, state: &State<AppState>, name: &str) -> Result<(), CarbonLintError> {
+    let mut sessions = state.profiling_sessions.lock()?;
+    if sessions.get(name).ok_or_else(|| no_session(name))?.stopping {
+        return Err(session_stopping(name));
+    }
+    let session = sessions.remove(name).ok_or_else(|| no_session(name))?;
+    drop(sessions);
+    let _ = fs::remove_file(session_checkpoint_path(app_handle, &session.id)?);
+    Ok(())
+}
+
+/// Starts or stops the default session from the tray or global shortcut,
+/// reporting the outcome as a notification since there is no caller to
+/// return it to. Runs on its own thread, since those handlers run on the
+/// main thread and saving a run may wait on the network.
+pub(crate) fn toggle_profiling(app: &tauri::AppHandle) {
+    let app = app.clone();
+    thread::spawn(move || toggle_profiling_now(&app));
+}
+
+fn toggle_profiling_now(app: &tauri::AppHandle) {
+    let state = app.state::<AppState>();
+    let is_profiling = state.profiling_sessions.lock().map(|s| s.contains_key(DEFAULT_SESSION));
+    let outcome = match is_profiling {
+        Ok(true) => stop_profiling_impl(app, &state, DEFAULT_SESSION, StopReason::Manual)
+            .map(|_| "Profiling stopped & saved!".to_string()),
+        Ok(false) => {
+            let options = StartProfilingOptions { command: Some("Global Shortcut".to_string()), ..Default::default() };
+            start_profiling_impl(app, &state, options).map(|_| "Profiling started!".to_string())
+        }
+        Err(e) => Err(e.into()),
+    };
+    notify(app, outcome.unwrap_or_else(|e| format!("Profiling failed: {}", e.message())));
+}
+
+pub(crate) fn notify(app: &tauri::AppHandle, body: String) {
+    let _ = app.notification()
+        .builder()
+        .title("CarbonLint")
+        .body(body)
+        .show();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_714_557_600_000;
+
+    fn gap(event: Option<ClockEvent>) -> Option<(i64, i64, GapKind)> {
+        match event {
+            Some(ClockEvent::Gap(gap)) => Some((gap.start, gap.end, gap.kind)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn detect_clock_event_ignores_on_time_ticks_and_jitter() {
+        assert!(detect_clock_event(2_000, 2_000, 2_000, NOW).is_none());
+        assert!(detect_clock_event(3_500, 2_000, 2_000, NOW).is_none());
+        assert!(detect_clock_event(1_000, 2_000, 2_000, NOW).is_none());
+    }
+
+    #[test]
+    fn detect_clock_event_reports_suspend_when_boot_clock_runs_ahead() {
+        let event = detect_clock_event(62_000, 2_000, 62_000, NOW);
+        assert_eq!(gap(event), Some((NOW - 60_000, NOW, GapKind::Suspend)));
+    }
+
+    #[test]
+    fn detect_clock_event_counts_stalls_as_session_time() {
+        // Both clocks confirm the wall time, so the machine was awake.
+        assert!(detect_clock_event(30_000, 30_000, 30_000, NOW).is_none());
+    }
+
+    #[test]
+    fn detect_clock_event_reports_unconfirmed_forward_jumps() {
+        let event = detect_clock_event(3_600_000, 2_000, 2_000, NOW);
+        assert_eq!(gap(event), Some((NOW - 3_598_000, NOW, GapKind::ClockJump)));
+    }
+
+    #[test]
+    fn detect_clock_event_shifts_sessions_when_the_clock_goes_back() {
+        match detect_clock_event(-60_000, 2_000, 2_000, NOW) {
+            Some(ClockEvent::Shift(delta_ms)) => assert_eq!(delta_ms, -62_000),
+            _ => panic!("expected a shift"),
+        }
+    }
+
+    fn session_at(start_time: i64, stop_conditions: StopConditions) -> ProfilingSession {
+        ProfilingSession {
+            id: "cl_1".into(),
+            name: DEFAULT_SESSION.into(),
+            start_time,
+            start_timestamp: millis_to_rfc3339(start_time),
+            command: "cargo build".into(),
+            project: "carbonlint".into(),
+            branch: "main".into(),
+            commit: "abc1234".into(),
+            samples: SampleBuffer::with_cap(64),
+            paused_at: None,
+            pauses: Vec::new(),
+            phases: Vec::new(),
+            stop_conditions,
+            gaps: Vec::new(),
+            trigger: None,
+            repository: None,
+            stopping: false,
+            stop_failed: false,
+        }
+    }
+
+    fn record(session: &mut ProfilingSession, timestamp: i64, cpu_utilization: f32) {
+        session.samples.push(ResourceSample { cpu_utilization, ..crate::tests::sample(timestamp) });
+    }
+
+    #[test]
+    fn check_stop_conditions_counts_only_active_time_towards_max_duration() {
+        let ten_minutes = StopConditions { max_duration_minutes: Some(10.0), ..Default::default() };
+        let mut session = session_at(NOW - 600_000, ten_minutes);
+        assert_eq!(check_stop_conditions(&session, NOW - 1, None, None), None);
+        assert_eq!(check_stop_conditions(&session, NOW, None, None), Some(StopReason::MaxDuration));
+
+        session.pauses.push(PauseInterval { start: NOW - 300_000, end: NOW - 240_000 });
+        session.gaps.push(TimeGap { start: NOW - 200_000, end: NOW - 170_000, kind: GapKind::Suspend });
+        assert_eq!(check_stop_conditions(&session, NOW, None, None), None);
+        assert_eq!(check_stop_conditions(&session, NOW + 90_000, None, None), Some(StopReason::MaxDuration));
+
+        // Nothing but a process exit ends a paused session.
+        session.paused_at = Some(NOW);
+        assert_eq!(check_stop_conditions(&session, NOW + 600_000, None, Some(true)), None);
+        assert_eq!(check_stop_conditions(&session, NOW + 600_000, None, Some(false)), Some(StopReason::ProcessExited));
+    }
+
+    #[test]
+    fn check_stop_conditions_skips_sessions_already_stopping_or_failed() {
+        let conditions = StopConditions { max_duration_minutes: Some(0.0), ..Default::default() };
+        let mut session = session_at(NOW - 1_000, conditions);
+        assert_eq!(check_stop_conditions(&session, NOW, None, Some(false)), Some(StopReason::ProcessExited));
+
+        session.stopping = true;
+        assert_eq!(check_stop_conditions(&session, NOW, None, Some(false)), None);
+        session.stopping = false;
+        session.stop_failed = true;
+        assert_eq!(check_stop_conditions(&session, NOW, None, Some(false)), None);
+    }
+
+    #[test]
+    fn check_stop_conditions_needs_settings_for_energy_and_carbon_limits() {
+        let energy = StopConditions { max_energy_kwh: Some(0.0), ..Default::default() };
+        let session = session_at(NOW - 60_000, energy);
+        assert_eq!(check_stop_conditions(&session, NOW, None, None), None);
+        let settings = AppSettings::default();
+        assert_eq!(check_stop_conditions(&session, NOW, Some(&settings), None), Some(StopReason::EnergyLimit));
+
+        let carbon = StopConditions { max_carbon_grams: Some(0.0), max_energy_kwh: Some(1e9), ..Default::default() };
+        let session = session_at(NOW - 60_000, carbon);
+        assert_eq!(check_stop_conditions(&session, NOW, Some(&settings), None), Some(StopReason::CarbonLimit));
+    }
+
+    #[test]
+    fn check_stop_conditions_waits_for_a_full_idle_window() {
+        let idle = StopConditions { idle_minutes: Some(1.0), idle_cpu_percent: 5.0, ..Default::default() };
+        let mut session = session_at(NOW - 120_000, idle);
+        for t in (NOW - 30_000..=NOW).step_by(2_000) {
+            record(&mut session, t, 1.0);
+        }
+        // Quiet, but the samples don't reach back a whole minute yet.
+        assert_eq!(check_stop_conditions(&session, NOW, None, None), None);
+
+        let mut session = session_at(NOW - 120_000, session.stop_conditions.clone());
+        for t in (NOW - 90_000..=NOW).step_by(2_000) {
+            record(&mut session, t, if t == NOW - 80_000 { 90.0 } else { 1.0 });
+        }
+        assert_eq!(check_stop_conditions(&session, NOW, None, None), Some(StopReason::Idle));
+        record(&mut session, NOW + 2_000, 5.0);
+        assert_eq!(check_stop_conditions(&session, NOW + 2_000, None, None), None);
+    }
+
+    fn active_ms(session: &ProfilingSession, now: i64) -> i64 {
+        now - session.start_time - paused_ms_between(&session_excluded(session, now), session.start_time, now)
+    }
+
+    #[test]
+    fn clock_set_back_shifts_the_whole_session_timeline() {
+        let mut session = session_at(NOW - 60_000, StopConditions::default());
+        record(&mut session, NOW - 58_000, 10.0);
+        session.pauses.push(PauseInterval { start: NOW - 50_000, end: NOW - 40_000 });
+        session.gaps.push(TimeGap { start: NOW - 30_000, end: NOW - 20_000, kind: GapKind::ClockJump });
+        session.phases.push(PhaseMarker { name: "test".into(), timestamp: NOW - 10_000 });
+        session.paused_at = Some(NOW - 5_000);
+        let before = active_ms(&session, NOW);
+
+        // One tick after NOW the wall clock reads an hour earlier.
+        let now = NOW + 2_000 - 3_600_000;
+        let Some(ClockEvent::Shift(delta_ms)) = detect_clock_event(now - NOW, 2_000, 2_000, now) else {
+            panic!("expected a shift");
+        };
+        assert_eq!(delta_ms, -3_600_000);
+        shift_session_clock(&mut session, delta_ms);
+
+        let shifted = |t: i64| t - 3_600_000;
+        assert_eq!(session.start_time, shifted(NOW - 60_000));
+        assert_eq!(session.start_timestamp, millis_to_rfc3339(shifted(NOW - 60_000)));
+        assert_eq!(session.samples.entries[0].sample.timestamp, shifted(NOW - 58_000));
+        assert_eq!((session.pauses[0].start, session.pauses[0].end), (shifted(NOW - 50_000), shifted(NOW - 40_000)));
+        assert_eq!((session.gaps[0].start, session.gaps[0].end), (shifted(NOW - 30_000), shifted(NOW - 20_000)));
+        assert_eq!(session.phases[0].timestamp, shifted(NOW - 10_000));
+        assert_eq!(session.paused_at, Some(shifted(NOW - 5_000)));
+        // Two seconds later by the new clock, still paused: nothing more counted.
+        assert_eq!(active_ms(&session, now), before);
+    }
+
+    #[test]
+    fn awake_and_boot_clocks_move_forward_together() {
+        let (awake, boot) = awake_and_boot_ms();
+        thread::sleep(Duration::from_millis(20));
+        let (awake_later, boot_later) = awake_and_boot_ms();
+        assert!(awake_later - awake >= 10, "awake clock moved {} ms", awake_later - awake);
+        // Without a suspend in between the two clocks agree.
+        assert!(((boot_later - boot) - (awake_later - awake)).abs() <= 1_000);
+    }
+}
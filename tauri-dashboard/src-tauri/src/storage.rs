@@ -0,0 +1,1174 @@
+//! Everything kept in the app data directory: schema migrations, atomic
+//! writes and recovery, the run database and the settings file.
+
+use super::*;
+
+// ============================================
+// Schema Versions & Migrations
+// ============================================
+
+pub(crate) const SETTINGS_SCHEMA_VERSION: u32 = 1;
+pub(crate) const RUN_SCHEMA_VERSION: u32 = 1;
+
+/// `MIGRATIONS[i]` upgrades a document from version `i` to `i + 1`.
+/// Documents written before versioning existed count as version 0.
+type Migration = fn(&mut serde_json::Value);
+
+const SETTINGS_MIGRATIONS: &[Migration] = &[
+    // v1 only introduces the stamp.
+    |_| {},
+];
+
+const RUN_MIGRATIONS: &[Migration] = &[
+    // v1 records the hardware profile a run was computed with.
+    |doc| {
+        if doc.get("hardwareProfile").is_none() {
+            doc["hardwareProfile"] = serde_json::json!("unknown");
+        }
+    },
+];
+
+/// Upgrades `doc` in place and returns the version it was written with.
+fn migrate_document(doc: &mut serde_json::Value, migrations: &[Migration], current: u32) -> Result<u32, String> {
+    if !doc.is_object() {
+        return Err("expected a JSON object".to_string());
+    }
+    let version = doc.get("schemaVersion").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    if version > current {
+        return Err(format!("written by a newer version (schema {}, this build reads up to {})", version, current));
+    }
+    for migration in &migrations[version as usize..current as usize] {
+        migration(doc);
+    }
+    doc["schemaVersion"] = serde_json::json!(current);
+    Ok(version)
+}
+
+fn decode_run(data: &str) -> Result<RunResult, String> {
+    let mut doc: serde_json::Value = serde_json::from_str(data).map_err(|e| e.to_string())?;
+    migrate_document(&mut doc, RUN_MIGRATIONS, RUN_SCHEMA_VERSION)?;
+    serde_json::from_value(doc).map_err(|e| e.to_string())
+}
+
+/// Data that could not be read. The underlying file or row is left alone so
+/// nothing is lost; the dashboard lists these via `get_storage_issues`.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StorageIssue {
+    source: String,
+    id: Option<String>,
+    message: String,
+}
+
+#[derive(Default)]
+pub(crate) struct StorageIssues {
+    pub(crate) issues: Mutex<Vec<StorageIssue>>,
+}
+
+impl StorageIssues {
+    pub(crate) fn report(&self, source: &str, id: Option<&str>, message: String) {
+        let issue = StorageIssue {
+            source: source.to_string(),
+            id: id.map(str::to_string),
+            message,
+        };
+        // A panic elsewhere shouldn't stop issues from being recorded.
+        let mut issues = self.issues.lock().unwrap_or_else(|e| e.into_inner());
+        if !issues.contains(&issue) {
+            issues.push(issue);
+        }
+    }
+}
+
+/// `<path>.<suffix>`, e.g. `settings.json.v0.bak` for suffix `v0.bak`.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(format!(".{}", suffix));
+    PathBuf::from(sibling)
+}
+
+/// Copies `path` to `<path>.<suffix>.bak` before it is rewritten.
+pub(crate) fn backup_file(path: &Path, suffix: &str) -> Result<(), CarbonLintError> {
+    write_atomic(&sibling_path(path, &format!("{}.bak", suffix)), &fs::read(path)?)
+}
+
+// ============================================
+// Data Directory
+// ============================================
+
+/// `file` inside the app data directory, creating the directory if needed.
+pub(crate) fn app_data_file(app_handle: &tauri::AppHandle, file: &str) -> Result<PathBuf, CarbonLintError> {
+    let path = app_handle
+        .path()
+        .app_data_dir()
+        .map_err(|e| CarbonLintError::StorageIo(e.to_string()))?;
+    fs::create_dir_all(&path)?;
+    Ok(path.join(file))
+}
+
+fn get_runs_path(app_handle: &tauri::AppHandle) -> Result<PathBuf, CarbonLintError> {
+    app_data_file(app_handle, "runs.json")
+}
+
+fn get_settings_path(app_handle: &tauri::AppHandle) -> Result<PathBuf, CarbonLintError> {
+    app_data_file(app_handle, "settings.json")
+}
+
+pub(crate) fn get_intensity_profiles_path(app_handle: &tauri::AppHandle) -> Result<PathBuf, CarbonLintError> {
+    app_data_file(app_handle, "intensity_profiles.json")
+}
+
+/// Writes `contents` to a temp file, syncs it and renames it over `path`, so
+/// a crash leaves either the old file or the new one, never a truncated one.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), CarbonLintError> {
+    let tmp = sibling_path(path, "tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    // The rename is only durable once the directory entry is synced.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Advisory lock on the app data directory, held across read-modify-write
+/// cycles so a second instance (or the CLI) can't interleave its edits.
+/// Released on drop. Don't acquire it twice on one thread: the second
+/// acquire blocks on the first.
+pub(crate) struct DataDirLock(fs::File);
+
+impl DataDirLock {
+    pub(crate) fn acquire(app_handle: &tauri::AppHandle) -> Result<Self, CarbonLintError> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(app_data_file(app_handle, ".lock")?)?;
+        file.lock()?;
+        Ok(DataDirLock(file))
+    }
+}
+
+impl Drop for DataDirLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// Backups of `path` (`<path>.*.bak`, excluding set-aside corrupt copies)
+/// that pass `is_good`, newest first.
+fn latest_good_backup(path: &Path, is_good: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+    let dir = path.parent()?;
+    let prefix = format!("{}.", path.file_name()?.to_string_lossy());
+    let mut backups: Vec<(std::time::SystemTime, PathBuf)> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.starts_with(&prefix) && name.ends_with(".bak") && !name.contains(".corrupt-")
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    backups.sort_by_key(|b| std::cmp::Reverse(b.0));
+    backups.into_iter().map(|(_, p)| p).find(|p| is_good(p))
+}
+
+pub(crate) fn json_file_is_good(path: &Path) -> bool {
+    fs::read(path)
+        .ok()
+        .is_some_and(|content| serde_json::from_slice::<serde_json::Value>(&content).is_ok())
+}
+
+fn sqlite_file_is_good(path: &Path) -> bool {
+    rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|conn| conn.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0)))
+        .is_ok_and(|result| result == "ok")
+}
+
+/// If `path` exists but fails `is_good`, moves it (and any `companions`, such
+/// as SQLite's `-wal`) aside as `.corrupt-<ts>.bak` and restores the newest
+/// good backup in its place. Without a backup the file is only moved aside,
+/// so the app starts with a fresh one.
+fn recover_file(
+    path: &Path,
+    companions: &[&str],
+    source: &str,
+    is_good: impl Fn(&Path) -> bool,
+    issues: &StorageIssues,
+) -> Result<(), CarbonLintError> {
+    if !path.exists() || is_good(path) {
+        return Ok(());
+    }
+    let backup = latest_good_backup(path, &is_good);
+    let stamp = Utc::now().format("%Y%m%d%H%M%S");
+    let set_aside = sibling_path(path, &format!("corrupt-{}.bak", stamp));
+    for companion in std::iter::once("").chain(companions.iter().copied()) {
+        let file = PathBuf::from(format!("{}{}", path.display(), companion));
+        if file.exists() {
+            fs::rename(&file, sibling_path(&file, &format!("corrupt-{}.bak", stamp)))?;
+        }
+    }
+    let message = match backup {
+        Some(backup) => {
+            write_atomic(path, &fs::read(&backup)?)?;
+            format!("File was unreadable and has been restored from {}", backup.display())
+        }
+        None => format!(
+            "File was unreadable and no good backup was found; it was moved to {} and a new one started",
+            set_aside.display()
+        ),
+    };
+    issues.report(source, None, message);
+    Ok(())
+}
+
+/// Writes a known-good copy of the run database to `runs.db.last-good.bak`
+/// unless the current one is less than a day old.
+fn snapshot_run_store(db: &Path) -> Result<(), CarbonLintError> {
+    let snapshot = sibling_path(db, "last-good.bak");
+    let stale = fs::metadata(&snapshot)
+        .and_then(|m| m.modified())
+        .map(|t| t.elapsed().unwrap_or_default() > Duration::from_secs(24 * 3600))
+        .unwrap_or(true);
+    if stale {
+        let tmp = sibling_path(&snapshot, "tmp");
+        let _ = fs::remove_file(&tmp);
+        rusqlite::Connection::open(db)?.execute("VACUUM INTO ?1", [tmp.to_string_lossy()])?;
+        fs::rename(&tmp, &snapshot)?;
+    }
+    Ok(())
+}
+
+/// Startup check of everything in the data directory: unreadable files are
+/// restored from their newest good backup (or set aside), and a good run
+/// database gets a fresh snapshot at most once a day.
+pub(crate) fn check_data_integrity(app_handle: &tauri::AppHandle, issues: &StorageIssues) -> Result<(), CarbonLintError> {
+    let _lock = DataDirLock::acquire(app_handle)?;
+    recover_file(&get_settings_path(app_handle)?, &[], "settings", json_file_is_good, issues)?;
+    recover_file(&get_intensity_profiles_path(app_handle)?, &[], "intensity_profiles", json_file_is_good, issues)?;
+
+    let db = get_run_store_path(app_handle)?;
+    recover_file(&db, &["-wal", "-shm"], "runs", sqlite_file_is_good, issues)?;
+    if db.exists() && sqlite_file_is_good(&db) {
+        if let Err(e) = snapshot_run_store(&db) {
+            issues.report("runs", None, format!("Could not snapshot the run database: {}", e));
+        }
+    }
+    Ok(())
+}
+
+// ============================================
+// Run Storage
+// ============================================
+
+/// Aggregates from `RunStore::totals`.
+pub(crate) struct RunTotals {
+    pub(crate) runs: usize,
+    pub(crate) carbon_grams: f64,
+    pub(crate) energy_kwh: f64,
+    pub(crate) water_liters: f64,
+    pub(crate) cost: f64,
+    pub(crate) cost_in_periods: [f64; 3],
+    /// Carbon of the two most recent runs, newest first.
+    pub(crate) latest_carbon: Vec<f64>,
+}
+
+/// SQLite-backed run history. Each run is stored as its JSON document, with
+/// the fields we filter on copied into indexed columns.
+pub(crate) struct RunStore {
+    conn: Mutex<rusqlite::Connection>,
+    issues: Arc<StorageIssues>,
+}
+
+const RUN_COLUMNS: &str = "id, project, branch, commit_sha, command, timestamp, data, started_ms";
+
+fn started_ms(timestamp: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(timestamp).ok().map(|t| t.timestamp_millis())
+}
+
+impl RunStore {
+    fn open(path: &std::path::Path, issues: Arc<StorageIssues>) -> rusqlite::Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        // Another instance may hold the write lock briefly; wait rather than fail.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS runs (
+                 id TEXT PRIMARY KEY,
+                 project TEXT NOT NULL,
+                 branch TEXT NOT NULL,
+                 commit_sha TEXT NOT NULL,
+                 command TEXT NOT NULL,
+                 timestamp TEXT NOT NULL,
+                 data TEXT NOT NULL,
+                 started_ms INTEGER
+             );
+             CREATE TABLE IF NOT EXISTS run_samples (
+                 run_id TEXT PRIMARY KEY,
+                 downsampled INTEGER NOT NULL DEFAULT 0,
+                 created_at TEXT NOT NULL,
+                 data TEXT NOT NULL
+             );",
+        )?;
+        Self::add_started_ms(&conn)?;
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS runs_project ON runs (project);
+             CREATE INDEX IF NOT EXISTS runs_branch ON runs (branch);
+             CREATE INDEX IF NOT EXISTS runs_commit ON runs (commit_sha);
+             CREATE INDEX IF NOT EXISTS runs_timestamp ON runs (timestamp);
+             CREATE INDEX IF NOT EXISTS runs_started ON runs (started_ms);",
+        )?;
+        Ok(RunStore { conn: Mutex::new(conn), issues })
+    }
+
+    /// Databases from before `started_ms` existed get the column, filled in
+    /// from each run's timestamp. Unparseable timestamps stay NULL.
+    fn add_started_ms(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        if conn.prepare("SELECT 1 FROM pragma_table_info('runs') WHERE name = 'started_ms'")?.exists([])? {
+            return Ok(());
+        }
+        let tx = conn.unchecked_transaction()?;
+        tx.execute("ALTER TABLE runs ADD COLUMN started_ms INTEGER", [])?;
+        let timestamps: Vec<(String, String)> = {
+            let mut stmt = tx.prepare("SELECT id, timestamp FROM runs")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        for (id, timestamp) in timestamps {
+            tx.execute("UPDATE runs SET started_ms = ?2 WHERE id = ?1", rusqlite::params![id, started_ms(&timestamp)])?;
+        }
+        tx.commit()
+    }
+
+    fn decode(&self, id: &str, data: &str) -> Option<RunResult> {
+        decode_run(data)
+            .map_err(|e| self.issues.report("runs", Some(id), e))
+            .ok()
+    }
+
+    /// Upgrades stored run documents to `RUN_SCHEMA_VERSION`, snapshotting
+    /// the database to `backup` first. Rows that fail to migrate are left
+    /// as they are and reported.
+    fn migrate_documents(&self, backup: &std::path::Path) -> Result<usize, CarbonLintError> {
+        let mut conn = self.conn.lock()?;
+        let stale: Vec<(String, String)> = {
+            let mut stmt = conn.prepare(
+                "SELECT id, data FROM runs
+                 WHERE json_valid(data) AND IFNULL(json_extract(data, '$.schemaVersion'), 0) < ?1",
+            )?;
+            let rows = stmt.query_map([RUN_SCHEMA_VERSION], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let migrated: Vec<RunResult> = stale
+            .into_iter()
+            .filter_map(|(id, data)| decode_run(&data).map_err(|e| self.issues.report("runs", Some(&id), e)).ok())
+            .collect();
+        if migrated.is_empty() {
+            return Ok(0);
+        }
+
+        conn.execute("VACUUM INTO ?1", [backup.to_string_lossy()])?;
+        let tx = conn.transaction()?;
+        for run in &migrated {
+            Self::upsert(&tx, run)?;
+        }
+        tx.commit()?;
+        Ok(migrated.len())
+    }
+
+    fn upsert(conn: &rusqlite::Connection, run: &RunResult) -> rusqlite::Result<()> {
+        let data = serde_json::to_string(run).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        conn.execute(
+            &format!(
+                "INSERT INTO runs ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (id) DO UPDATE SET project = ?2, branch = ?3, commit_sha = ?4,
+                     command = ?5, timestamp = ?6, data = ?7, started_ms = ?8",
+                RUN_COLUMNS
+            ),
+            rusqlite::params![
+                run.id,
+                run.project,
+                run.branch,
+                run.commit,
+                run.command,
+                run.timestamp,
+                data,
+                started_ms(&run.timestamp)
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn insert(&self, run: &RunResult, samples: &[ResourceSample]) -> Result<(), CarbonLintError> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction()?;
+        Self::upsert(&tx, run)?;
+        if !samples.is_empty() {
+            Self::write_samples(&tx, &run.id, samples, false)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn write_samples(conn: &rusqlite::Connection, run_id: &str, samples: &[ResourceSample], downsampled: bool) -> rusqlite::Result<()> {
+        let data = serde_json::to_string(&SampleColumns::from(samples))
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        conn.execute(
+            "INSERT OR REPLACE INTO run_samples (run_id, downsampled, created_at, data) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![run_id, downsampled, Utc::now().to_rfc3339(), data],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn load_samples(&self, run_id: &str) -> Result<Option<Vec<ResourceSample>>, CarbonLintError> {
+        let conn = self.conn.lock()?;
+        let data: Option<String> = conn
+            .query_row("SELECT data FROM run_samples WHERE run_id = ?1", [run_id], |row| row.get(0))
+            .map(Some)
+            .or_else(|e| if e == rusqlite::Error::QueryReturnedNoRows { Ok(None) } else { Err(e) })?;
+        let Some(data) = data else {
+            return Ok(None);
+        };
+        decode_samples(&data).map(Some).map_err(|e| {
+            self.issues.report("run_samples", Some(run_id), e.clone());
+            CarbonLintError::CorruptData(format!("Stored samples for run {} are unreadable: {}", run_id, e))
+        })
+    }
+
+    /// Samples to re-derive a run from. Empty when none are stored or the
+    /// stored series is corrupt (which `load_samples` reports), so callers
+    /// fall back to the run's aggregate metrics.
+    pub(crate) fn samples_or_empty(&self, run_id: &str) -> Result<Vec<ResourceSample>, CarbonLintError> {
+        match self.load_samples(run_id) {
+            Ok(samples) => Ok(samples.unwrap_or_default()),
+            Err(CarbonLintError::CorruptData(_)) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Downsamples full-resolution series stored before `cutoff` to at most
+    /// `points` samples each.
+    pub(crate) fn apply_sample_retention(&self, cutoff: &str, points: usize) -> Result<usize, CarbonLintError> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction()?;
+        let expired: Vec<(String, String)> = {
+            let mut stmt = tx.prepare("SELECT run_id, data FROM run_samples WHERE downsampled = 0 AND created_at < ?1")?;
+            let rows = stmt.query_map([cutoff], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let mut count = 0;
+        for (run_id, data) in expired {
+            // Left at full resolution so nothing more is lost; listed for the user.
+            let samples = match decode_samples(&data) {
+                Ok(samples) => samples,
+                Err(e) => {
+                    self.issues.report("run_samples", Some(&run_id), e);
+                    continue;
+                }
+            };
+            Self::write_samples(&tx, &run_id, &downsample(&samples, points), true)?;
+            count += 1;
+        }
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// Writes all runs in one transaction; either every run lands or none do.
+    pub(crate) fn save_all(&self, runs: &[RunResult]) -> Result<(), CarbonLintError> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction()?;
+        for run in runs {
+            Self::upsert(&tx, run)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Runs matching `filter`, oldest first unless it asks for the newest
+    /// first, paged by its `offset` and `limit`.
+    pub(crate) fn query(&self, filter: &RunFilter) -> Result<Vec<RunResult>, CarbonLintError> {
+        // A bound that doesn't parse matches nothing, as does a run whose
+        // timestamp doesn't (its `started_ms` is NULL).
+        let bound = |bound: &Option<String>| {
+            bound.as_deref().map(|b| started_ms(b).map_or(rusqlite::types::Value::Null, rusqlite::types::Value::Integer))
+        };
+        let text = |value: &Option<String>| value.clone().map(rusqlite::types::Value::Text);
+
+        let mut clauses = Vec::new();
+        let mut values = Vec::new();
+        for (column, value, op) in [
+            ("project", text(&filter.project), "="),
+            ("branch", text(&filter.branch), "="),
+            ("commit_sha", text(&filter.commit), "="),
+            ("command", text(&filter.command), "="),
+            ("started_ms", bound(&filter.since), ">="),
+            ("started_ms", bound(&filter.until), "<="),
+        ] {
+            if let Some(value) = value {
+                values.push(value);
+                clauses.push(format!("{} {} ?{}", column, op, values.len()));
+            }
+        }
+        let where_clause = if clauses.is_empty() { String::new() } else { format!("WHERE {}", clauses.join(" AND ")) };
+        let order = if filter.newest_first { "DESC" } else { "ASC" };
+        // SQLite only takes OFFSET after a LIMIT; -1 means no limit.
+        let limit = filter.limit.map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+        let offset = filter.offset.map_or(0, |offset| i64::try_from(offset).unwrap_or(i64::MAX));
+
+        let conn = self.conn.lock()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, data FROM runs {} ORDER BY started_ms {order}, rowid {order} LIMIT {} OFFSET {}",
+            where_clause,
+            limit,
+            offset,
+            order = order
+        ))?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(values), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut runs = Vec::new();
+        for row in rows {
+            let (id, data) = row?;
+            if let Some(run) = self.decode(&id, &data) {
+                runs.push(run);
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Totals over every stored run, summed in SQL so the documents are not
+    /// decoded. Costs only count in `currency`, and each of `cost_since`
+    /// (epoch ms) gets the cost of runs started at or after it.
+    pub(crate) fn totals(&self, currency: &str, cost_since: [i64; 3]) -> Result<RunTotals, CarbonLintError> {
+        let conn = self.conn.lock()?;
+        let (runs, carbon_grams, energy_kwh, water_liters) = conn.query_row(
+            "SELECT COUNT(*),
+                    TOTAL(json_extract(data, '$.carbon.total_grams')),
+                    TOTAL(json_extract(data, '$.energy.total_kwh')),
+                    TOTAL(json_extract(data, '$.water.totalLiters'))
+             FROM runs",
+            [],
+            |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        let (cost, cost_in_periods) = conn.query_row(
+            "SELECT TOTAL(cost),
+                    TOTAL(CASE WHEN started_ms >= ?2 THEN cost END),
+                    TOTAL(CASE WHEN started_ms >= ?3 THEN cost END),
+                    TOTAL(CASE WHEN started_ms >= ?4 THEN cost END)
+             FROM (SELECT json_extract(data, '$.cost.totalCost') AS cost, started_ms FROM runs
+                   WHERE json_extract(data, '$.cost.currency') = ?1)",
+            rusqlite::params![currency, cost_since[0], cost_since[1], cost_since[2]],
+            |row| Ok((row.get(0)?, [row.get(1)?, row.get(2)?, row.get(3)?])),
+        )?;
+        let mut stmt = conn.prepare(
+            "SELECT IFNULL(json_extract(data, '$.carbon.total_grams'), 0.0) FROM runs
+             ORDER BY started_ms DESC, rowid DESC LIMIT 2",
+        )?;
+        let latest_carbon = stmt.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(RunTotals {
+            runs: runs as usize,
+            carbon_grams,
+            energy_kwh,
+            water_liters,
+            cost,
+            cost_in_periods,
+            latest_carbon,
+        })
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Result<Option<RunResult>, CarbonLintError> {
+        let conn = self.conn.lock()?;
+        let data: Option<String> = conn
+            .query_row("SELECT data FROM runs WHERE id = ?1", [id], |row| row.get(0))
+            .map(Some)
+            .or_else(|e| if e == rusqlite::Error::QueryReturnedNoRows { Ok(None) } else { Err(e) })?;
+        Ok(data.and_then(|d| self.decode(id, &d)))
+    }
+
+    pub(crate) fn contains(&self, id: &str) -> Result<bool, CarbonLintError> {
+        let conn = self.conn.lock()?;
+        let exists = conn.prepare("SELECT 1 FROM runs WHERE id = ?1")?.exists([id])?;
+        Ok(exists)
+    }
+
+    /// Removes a run and its samples together.
+    pub(crate) fn delete(&self, id: &str) -> Result<bool, CarbonLintError> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM run_samples WHERE run_id = ?1", [id])?;
+        let deleted = tx.execute("DELETE FROM runs WHERE id = ?1", [id])? > 0;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn is_empty(&self) -> Result<bool, CarbonLintError> {
+        let conn = self.conn.lock()?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM runs", [], |row| row.get(0))?;
+        Ok(count == 0)
+    }
+}
+
+/// Column-oriented sample storage: one array per field, with timestamps
+/// stored as deltas from the previous sample. `counts` is left out while
+/// every sample is a single raw one.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct SampleColumns {
+    timestamp_deltas: Vec<i64>,
+    cpu_utilization: Vec<f32>,
+    memory_used: Vec<u64>,
+    memory_percent: Vec<f32>,
+    disk_read_per_sec: Vec<f64>,
+    disk_write_per_sec: Vec<f64>,
+    net_rx_per_sec: Vec<f64>,
+    net_tx_per_sec: Vec<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    counts: Vec<u32>,
+}
+
+impl From<&[ResourceSample]> for SampleColumns {
+    fn from(samples: &[ResourceSample]) -> Self {
+        let mut columns = SampleColumns::default();
+        let mut prev = 0;
+        for s in samples {
+            columns.timestamp_deltas.push(s.timestamp - prev);
+            prev = s.timestamp;
+            columns.cpu_utilization.push(s.cpu_utilization);
+            columns.memory_used.push(s.memory_used);
+            columns.memory_percent.push(s.memory_percent);
+            columns.disk_read_per_sec.push(s.disk_read_per_sec);
+            columns.disk_write_per_sec.push(s.disk_write_per_sec);
+            columns.net_rx_per_sec.push(s.net_rx_per_sec);
+            columns.net_tx_per_sec.push(s.net_tx_per_sec);
+            columns.counts.push(s.count);
+        }
+        if columns.counts.iter().all(|&c| c == 1) {
+            columns.counts.clear();
+        }
+        columns
+    }
+}
+
+impl SampleColumns {
+    fn into_samples(self) -> Vec<ResourceSample> {
+        let mut timestamp = 0;
+        (0..self.timestamp_deltas.len())
+            .map(|i| {
+                timestamp += self.timestamp_deltas[i];
+                ResourceSample {
+                    timestamp,
+                    cpu_utilization: self.cpu_utilization.get(i).copied().unwrap_or_default(),
+                    memory_used: self.memory_used.get(i).copied().unwrap_or_default(),
+                    memory_percent: self.memory_percent.get(i).copied().unwrap_or_default(),
+                    disk_read_per_sec: self.disk_read_per_sec.get(i).copied().unwrap_or_default(),
+                    disk_write_per_sec: self.disk_write_per_sec.get(i).copied().unwrap_or_default(),
+                    net_rx_per_sec: self.net_rx_per_sec.get(i).copied().unwrap_or_default(),
+                    net_tx_per_sec: self.net_tx_per_sec.get(i).copied().unwrap_or_default(),
+                    count: self.counts.get(i).copied().unwrap_or(1),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Parses a stored `SampleColumns` document, rejecting one whose columns
+/// disagree on the number of samples.
+fn decode_samples(data: &str) -> Result<Vec<ResourceSample>, String> {
+    let columns: SampleColumns = serde_json::from_str(data).map_err(|e| e.to_string())?;
+    let len = columns.timestamp_deltas.len();
+    let lengths = [
+        columns.cpu_utilization.len(),
+        columns.memory_used.len(),
+        columns.memory_percent.len(),
+        columns.disk_read_per_sec.len(),
+        columns.disk_write_per_sec.len(),
+        columns.net_rx_per_sec.len(),
+        columns.net_tx_per_sec.len(),
+    ];
+    if lengths.iter().any(|&l| l != len) || !(columns.counts.is_empty() || columns.counts.len() == len) {
+        return Err(format!(
+            "sample columns have different lengths ({} timestamps, {:?}, {} counts)",
+            len,
+            lengths,
+            columns.counts.len()
+        ));
+    }
+    Ok(columns.into_samples())
+}
+
+/// Averages consecutive samples into at most `max_points` buckets, keeping
+/// each bucket's peak memory and the number of raw samples it covers.
+pub(crate) fn downsample(samples: &[ResourceSample], max_points: usize) -> Vec<ResourceSample> {
+    if max_points == 0 || samples.len() <= max_points {
+        return samples.to_vec();
+    }
+    let bucket_size = samples.len().div_ceil(max_points);
+    samples
+        .chunks(bucket_size)
+        .map(|bucket| {
+            let (disk_read, disk_write, net_rx, net_tx) = average_io(bucket);
+            ResourceSample {
+                timestamp: bucket[0].timestamp,
+                cpu_utilization: weighted_mean(bucket, |s| s.cpu_utilization as f64) as f32,
+                memory_used: bucket.iter().map(|s| s.memory_used).max().unwrap_or(0),
+                memory_percent: weighted_mean(bucket, |s| s.memory_percent as f64) as f32,
+                disk_read_per_sec: disk_read,
+                disk_write_per_sec: disk_write,
+                net_rx_per_sec: net_rx,
+                net_tx_per_sec: net_tx,
+                count: bucket.iter().map(|s| s.count).sum(),
+            }
+        })
+        .collect()
+}
+
+fn get_run_store_path(app_handle: &tauri::AppHandle) -> Result<PathBuf, CarbonLintError> {
+    app_data_file(app_handle, "runs.db")
+}
+
+/// Opens the run store, upgrading stored documents and importing a legacy
+/// `runs.json` the first time. The old file is kept as `runs.json.migrated`.
+pub(crate) fn open_run_store(app_handle: &tauri::AppHandle, issues: Arc<StorageIssues>) -> Result<RunStore, CarbonLintError> {
+    let path = get_run_store_path(app_handle)?;
+    // Two instances starting together must not both import runs.json.
+    let _lock = DataDirLock::acquire(app_handle)?;
+    let store = RunStore::open(&path, issues.clone())?;
+    let backup = path.with_extension(format!("db.{}.bak", Utc::now().format("%Y%m%d%H%M%S")));
+    store.migrate_documents(&backup)?;
+
+    let legacy = get_runs_path(app_handle)?;
+    if legacy.exists() && store.is_empty()? {
+        let content = fs::read_to_string(&legacy)?;
+        let docs: Vec<serde_json::Value> = match serde_json::from_str(&content) {
+            Ok(docs) => docs,
+            Err(e) => {
+                // Leave the file in place so it can be repaired and re-imported.
+                issues.report("runs.json", None, e.to_string());
+                return Ok(store);
+            }
+        };
+        let runs: Vec<RunResult> = docs
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, doc)| {
+                decode_run(&doc.to_string())
+                    .map_err(|e| issues.report("runs.json", Some(&format!("#{}", i)), e))
+                    .ok()
+            })
+            .collect();
+        store.save_all(&runs)?;
+        fs::rename(&legacy, legacy.with_extension("json.migrated"))?;
+    }
+    Ok(store)
+}
+
+// ============================================
+// Settings File
+// ============================================
+
+/// Parses the settings file and upgrades it in memory; `None` when there
+/// is no file yet.
+fn read_settings_file(path: &Path) -> Result<Option<(u32, AppSettings)>, String> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut doc: serde_json::Value = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+    let version = migrate_document(&mut doc, SETTINGS_MIGRATIONS, SETTINGS_SCHEMA_VERSION)?;
+    let settings = serde_json::from_value(doc).map_err(|e| e.to_string())?;
+    Ok(Some((version, settings)))
+}
+
+/// Reads settings without writing anything; older files are upgraded in
+/// memory until `migrate_settings_file` rewrites them. A file that can't be
+/// read is reported and left untouched; defaults are used until the user
+/// saves.
+pub(crate) fn load_settings_from_disk(app_handle: &tauri::AppHandle) -> Result<AppSettings, CarbonLintError> {
+    match read_settings_file(&get_settings_path(app_handle)?) {
+        Ok(loaded) => Ok(loaded.map(|(_, settings)| settings).unwrap_or_default()),
+        Err(e) => {
+            if let Some(issues) = app_handle.try_state::<Arc<StorageIssues>>() {
+                issues.report("settings", None, e);
+            }
+            Ok(AppSettings::default())
+        }
+    }
+}
+
+/// Settings as last read or saved, so background threads polling them don't
+/// re-read the file on every tick. `save_settings_to_disk` keeps it current.
+#[derive(Default)]
+pub(crate) struct SettingsCache(Mutex<Option<AppSettings>>);
+
+/// The cached settings, read from disk the first time.
+pub(crate) fn current_settings(app_handle: &tauri::AppHandle) -> Result<AppSettings, CarbonLintError> {
+    let Some(cache) = app_handle.try_state::<SettingsCache>() else {
+        return load_settings_from_disk(app_handle);
+    };
+    let mut cached = cache.0.lock()?;
+    if let Some(settings) = cached.as_ref() {
+        return Ok(settings.clone());
+    }
+    let settings = load_settings_from_disk(app_handle)?;
+    *cached = Some(settings.clone());
+    Ok(settings)
+}
+
+/// Rewrites an older settings file at the current schema version, keeping
+/// the original as `settings.json.v<N>.bak`. Runs once at startup, so reads
+/// never need the `DataDirLock`.
+pub(crate) fn migrate_settings_file(app_handle: &tauri::AppHandle) -> Result<(), CarbonLintError> {
+    let path = get_settings_path(app_handle)?;
+    if let Ok(Some((version, settings))) = read_settings_file(&path) {
+        if version < SETTINGS_SCHEMA_VERSION {
+            backup_file(&path, &format!("v{}", version))?;
+            save_settings_to_disk(app_handle, &settings)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn save_settings_to_disk(app_handle: &tauri::AppHandle, settings: &AppSettings) -> Result<(), CarbonLintError> {
+    let path = get_settings_path(app_handle)?;
+    let _lock = DataDirLock::acquire(app_handle)?;
+    if path.exists() {
+        // Keep the previous good file for startup recovery, and a copy of a
+        // file we couldn't read rather than overwrite it.
+        let suffix = if json_file_is_good(&path) {
+            "last-good".to_string()
+        } else {
+            format!("corrupt-{}", Utc::now().format("%Y%m%d%H%M%S"))
+        };
+        backup_file(&path, &suffix)?;
+    }
+    let mut settings = settings.clone();
+    settings.schema_version = SETTINGS_SCHEMA_VERSION;
+    let json = serde_json::to_string_pretty(&settings)?;
+    write_atomic(&path, json.as_bytes())?;
+    if let Some(cache) = app_handle.try_state::<SettingsCache>() {
+        *cache.0.lock()? = Some(settings);
+    }
+    Ok(())
+}
+
+pub(crate) fn save_run_to_disk(app_handle: &tauri::AppHandle, run: &RunResult, samples: &[ResourceSample]) -> Result<(), CarbonLintError> {
+    app_handle.state::<RunStore>().insert(run, samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADD_NAME: &[Migration] = &[
+        |doc| doc["name"] = serde_json::json!("unnamed"),
+        |doc| doc["tags"] = serde_json::json!([]),
+    ];
+
+    #[test]
+    fn migrate_document_runs_pending_migrations_and_stamps_version() {
+        let mut doc = serde_json::json!({ "id": "a" });
+        assert_eq!(migrate_document(&mut doc, ADD_NAME, 2), Ok(0));
+        assert_eq!(doc, serde_json::json!({ "id": "a", "name": "unnamed", "tags": [], "schemaVersion": 2 }));
+
+        let mut doc = serde_json::json!({ "schemaVersion": 1, "name": "kept" });
+        assert_eq!(migrate_document(&mut doc, ADD_NAME, 2), Ok(1));
+        assert_eq!(doc, serde_json::json!({ "name": "kept", "tags": [], "schemaVersion": 2 }));
+    }
+
+    #[test]
+    fn migrate_document_leaves_current_documents_alone() {
+        let mut doc = serde_json::json!({ "schemaVersion": 2 });
+        assert_eq!(migrate_document(&mut doc, ADD_NAME, 2), Ok(2));
+        assert_eq!(doc, serde_json::json!({ "schemaVersion": 2 }));
+    }
+
+    #[test]
+    fn migrate_document_rejects_newer_versions_and_non_objects() {
+        let mut newer = serde_json::json!({ "schemaVersion": 3 });
+        assert!(migrate_document(&mut newer, ADD_NAME, 2).unwrap_err().contains("newer version"));
+        assert_eq!(newer, serde_json::json!({ "schemaVersion": 3 }));
+
+        let mut array = serde_json::json!([1, 2]);
+        assert!(migrate_document(&mut array, ADD_NAME, 2).is_err());
+    }
+
+    #[test]
+    fn decode_run_fills_in_hardware_profile_for_unversioned_runs() {
+        let mut doc = serde_json::to_value(crate::tests::run_at("2024-05-01T10:00:00Z")).unwrap();
+        let fields = doc.as_object_mut().unwrap();
+        fields.remove("schemaVersion");
+        fields.remove("hardwareProfile");
+
+        let run = decode_run(&doc.to_string()).unwrap();
+        assert_eq!(run.schemaVersion, RUN_SCHEMA_VERSION);
+        assert_eq!(run.hardwareProfile, "unknown");
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("carbonlint-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn recover_file_sets_aside_corrupt_files_without_a_backup() {
+        let dir = scratch_dir("recover-none");
+        let path = dir.join("settings.json");
+        fs::write(&path, "{ not json").unwrap();
+        let issues = StorageIssues::default();
+
+        recover_file(&path, &[], "settings", json_file_is_good, &issues).unwrap();
+        assert!(!path.exists());
+        let names: Vec<String> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        assert!(names.iter().any(|n| n.starts_with("settings.json.corrupt-") && n.ends_with(".bak")));
+        assert_eq!(issues.issues.lock().unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recover_file_restores_the_latest_good_backup() {
+        let dir = scratch_dir("recover-backup");
+        let path = dir.join("settings.json");
+        fs::write(sibling_path(&path, "last-good.bak"), r#"{ "region": "EU-NORTH" }"#).unwrap();
+        fs::write(&path, "{ not json").unwrap();
+        let issues = StorageIssues::default();
+
+        recover_file(&path, &[], "settings", json_file_is_good, &issues).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), r#"{ "region": "EU-NORTH" }"#);
+        assert!(issues.issues.lock().unwrap()[0].message.contains("restored"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A store in a scratch directory holding `run_at(timestamp)` under each id.
+    fn store_with(name: &str, runs: &[(&str, &str)]) -> (PathBuf, RunStore) {
+        let dir = scratch_dir(name);
+        let store = RunStore::open(&dir.join("runs.db"), Arc::default()).unwrap();
+        for &(id, timestamp) in runs {
+            let mut run = crate::tests::run_at(timestamp);
+            run.id = id.into();
+            store.insert(&run, &[]).unwrap();
+        }
+        (dir, store)
+    }
+
+    fn ids(runs: Vec<RunResult>) -> Vec<String> {
+        runs.into_iter().map(|r| r.id).collect()
+    }
+
+    #[test]
+    fn query_matches_fields_and_inclusive_bounds() {
+        let (dir, store) = store_with("query-fields", &[("a", "2024-05-01T10:00:00+00:00")]);
+        assert_eq!(ids(store.query(&RunFilter::default()).unwrap()), vec!["a"]);
+
+        let by_fields = RunFilter { project: Some("carbonlint".into()), branch: Some("main".into()), ..Default::default() };
+        assert_eq!(store.query(&by_fields).unwrap().len(), 1);
+        let other_branch = RunFilter { branch: Some("dev".into()), ..Default::default() };
+        assert!(store.query(&other_branch).unwrap().is_empty());
+
+        let at_bounds = RunFilter {
+            since: Some("2024-05-01T10:00:00Z".into()),
+            until: Some("2024-05-01T12:00:00+02:00".into()),
+            ..Default::default()
+        };
+        assert_eq!(store.query(&at_bounds).unwrap().len(), 1);
+        let later = RunFilter { since: Some("2024-05-01T10:00:01Z".into()), ..Default::default() };
+        assert!(store.query(&later).unwrap().is_empty());
+        let bad_bound = RunFilter { until: Some("yesterday".into()), ..Default::default() };
+        assert!(store.query(&bad_bound).unwrap().is_empty());
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn query_bounds_reject_runs_with_unparseable_timestamps() {
+        let (dir, store) = store_with("query-unparseable", &[("a", "not a time")]);
+        let by_project = RunFilter { project: Some("carbonlint".into()), ..Default::default() };
+        assert_eq!(store.query(&by_project).unwrap().len(), 1);
+        let bounded = RunFilter { since: Some("2024-01-01T00:00:00Z".into()), ..Default::default() };
+        assert!(store.query(&bounded).unwrap().is_empty());
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn query_bounds_timestamps_across_offsets_and_fractional_seconds() {
+        let (dir, store) = store_with(
+            "query-bounds",
+            &[
+                ("before", "2024-05-01T09:59:59.900+00:00"),
+                ("first", "2024-05-01T10:00:00.500+00:00"),
+                ("last", "2024-05-01T10:00:01+00:00"),
+                ("after", "2024-05-01T10:00:01.500+00:00"),
+            ],
+        );
+
+        // 10:00:00.2Z to 10:00:01Z inclusive, given in two different offsets.
+        let filter = RunFilter {
+            since: Some("2024-05-01T12:00:00.2+02:00".into()),
+            until: Some("2024-05-01T05:00:01-05:00".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(store.query(&filter).unwrap()), vec!["first", "last"]);
+
+        let unparseable = RunFilter { since: Some("yesterday".into()), ..Default::default() };
+        assert!(store.query(&unparseable).unwrap().is_empty());
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn query_pages_in_either_order() {
+        let runs: Vec<(String, String)> =
+            (0..5).map(|i| (format!("r{}", i), format!("2024-05-0{}T10:00:00+00:00", i + 1))).collect();
+        let runs: Vec<(&str, &str)> = runs.iter().map(|(id, ts)| (id.as_str(), ts.as_str())).collect();
+        let (dir, store) = store_with("query-pages", &runs);
+
+        let page = RunFilter { limit: Some(2), offset: Some(1), ..Default::default() };
+        assert_eq!(ids(store.query(&page).unwrap()), vec!["r1", "r2"]);
+        let newest = RunFilter { newest_first: true, limit: Some(2), ..Default::default() };
+        assert_eq!(ids(store.query(&newest).unwrap()), vec!["r4", "r3"]);
+        let skip_only = RunFilter { offset: Some(3), ..Default::default() };
+        assert_eq!(ids(store.query(&skip_only).unwrap()), vec!["r3", "r4"]);
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn totals_sum_in_sql_and_split_cost_by_period() {
+        let (dir, store) = store_with("totals", &[]);
+        for (id, timestamp, grams, currency) in [
+            ("old", "2024-04-01T10:00:00+00:00", 2.0, "EUR"),
+            ("new", "2024-05-01T10:00:00+00:00", 3.0, "EUR"),
+            ("usd", "2024-05-01T11:00:00+00:00", 4.0, "USD"),
+        ] {
+            let mut run = crate::tests::run_at(timestamp);
+            run.id = id.into();
+            run.carbon.total_grams = grams;
+            run.water.total_liters = 0.5;
+            run.cost = Some(CostResult {
+                currency: currency.into(),
+                price_per_kwh: 0.2,
+                billed_kwh: grams,
+                total_cost: grams / 10.0,
+                time_of_use: false,
+            });
+            store.insert(&run, &[]).unwrap();
+        }
+
+        let since = |t: &str| DateTime::parse_from_rfc3339(t).unwrap().timestamp_millis();
+        let totals = store
+            .totals("EUR", [since("2024-05-01T10:00:00Z"), since("2024-04-01T10:00:01Z"), 0])
+            .unwrap();
+        assert_eq!(totals.runs, 3);
+        assert_eq!((totals.carbon_grams, totals.energy_kwh, totals.water_liters), (9.0, 0.003, 1.5));
+        assert!((totals.cost - 0.5).abs() < 1e-12);
+        assert_eq!(totals.cost_in_periods.map(|c| (c * 10.0).round()), [3.0, 3.0, 5.0]);
+        assert_eq!(totals.latest_carbon, vec![4.0, 3.0]);
+
+        let (empty_dir, empty) = store_with("totals-empty", &[]);
+        let totals = empty.totals("EUR", [0; 3]).unwrap();
+        assert_eq!((totals.runs, totals.cost, totals.latest_carbon.len()), (0, 0.0, 0));
+        drop((store, empty));
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&empty_dir).unwrap();
+    }
+
+    #[test]
+    fn open_adds_started_ms_to_older_databases() {
+        let dir = scratch_dir("started-ms");
+        let path = dir.join("runs.db");
+        let run = crate::tests::run_at("2024-05-01T12:00:00+02:00");
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(&format!(
+                "CREATE TABLE runs (id TEXT PRIMARY KEY, project TEXT NOT NULL, branch TEXT NOT NULL,
+                     commit_sha TEXT NOT NULL, command TEXT NOT NULL, timestamp TEXT NOT NULL, data TEXT NOT NULL);
+                 INSERT INTO runs VALUES ('run-1', 'carbonlint', 'main', 'abc1234', 'cargo build',
+                     '2024-05-01T12:00:00+02:00', '{}');",
+                serde_json::to_string(&run).unwrap().replace('\'', "''")
+            ))
+            .unwrap();
+
+        let store = RunStore::open(&path, Arc::default()).unwrap();
+        let bounded = RunFilter { since: Some("2024-05-01T10:00:00Z".into()), ..Default::default() };
+        assert_eq!(ids(store.query(&bounded).unwrap()), vec!["run-1"]);
+        drop(store);
+        // Opening again finds the column and leaves it be.
+        assert!(RunStore::open(&path, Arc::default()).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delete_removes_a_run_with_its_samples() {
+        let (dir, store) = store_with("delete", &[]);
+        let run = crate::tests::run_at("2024-05-01T10:00:00+00:00");
+        store.insert(&run, &[crate::tests::sample(1000)]).unwrap();
+        assert!(store.contains("run-1").unwrap());
+
+        assert!(store.delete("run-1").unwrap());
+        assert!(!store.contains("run-1").unwrap());
+        assert!(store.load_samples("run-1").unwrap().is_none());
+        assert!(!store.delete("run-1").unwrap());
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sample_counts_are_stored_and_weight_downsampling() {
+        let dir = scratch_dir("sample-counts");
+        let store = RunStore::open(&dir.join("runs.db"), Arc::new(StorageIssues::default())).unwrap();
+        let merged = |timestamp, cpu_utilization, count| ResourceSample { cpu_utilization, count, ..crate::tests::sample(timestamp) };
+        let samples = [merged(1000, 10.0, 3), merged(4000, 40.0, 1)];
+        store.insert(&crate::tests::run_at("2024-05-01T10:00:00+00:00"), &samples).unwrap();
+
+        let loaded = store.load_samples("run-1").unwrap().unwrap();
+        assert_eq!(loaded.iter().map(|s| s.count).collect::<Vec<_>>(), vec![3, 1]);
+        let bucket = &downsample(&loaded, 1)[0];
+        assert_eq!((bucket.count, bucket.cpu_utilization), (4, 17.5));
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_sample_rows_are_reported_not_hidden() {
+        let dir = scratch_dir("corrupt-samples");
+        let issues = Arc::new(StorageIssues::default());
+        let store = RunStore::open(&dir.join("runs.db"), issues.clone()).unwrap();
+        let run = crate::tests::run_at("2024-05-01T10:00:00+00:00");
+        store.insert(&run, &[crate::tests::sample(1000), crate::tests::sample(3000)]).unwrap();
+        {
+            let conn = store.conn.lock().unwrap();
+            for (id, data) in [("bad-json", "{ not json"), ("bad-lengths", r#"{"timestamp_deltas":[1,2],"cpu_utilization":[1.0],
+                "memory_used":[],"memory_percent":[],"disk_read_per_sec":[],"disk_write_per_sec":[],
+                "net_rx_per_sec":[],"net_tx_per_sec":[]}"#)] {
+                conn.execute(
+                    "INSERT INTO run_samples (run_id, downsampled, created_at, data) VALUES (?1, 0, '2000-01-01', ?2)",
+                    [id, data],
+                )
+                .unwrap();
+            }
+        }
+
+        assert_eq!(store.load_samples("run-1").unwrap().unwrap().len(), 2);
+        assert!(store.load_samples("missing").unwrap().is_none());
+        for id in ["bad-json", "bad-lengths"] {
+            assert!(matches!(store.load_samples(id), Err(CarbonLintError::CorruptData(_))));
+            assert!(store.samples_or_empty(id).unwrap().is_empty());
+        }
+        let reported = |id: &str| issues.issues.lock().unwrap().iter().filter(|i| i.id.as_deref() == Some(id)).count();
+        assert_eq!((reported("bad-json"), reported("bad-lengths")), (1, 1));
+
+        // Retention downsamples the good series and leaves the corrupt ones as they are.
+        issues.issues.lock().unwrap().clear();
+        assert_eq!(store.apply_sample_retention("9999", 1).unwrap(), 1);
+        assert_eq!(store.load_samples("run-1").unwrap().unwrap().len(), 1);
+        assert_eq!((reported("bad-json"), reported("bad-lengths")), (1, 1));
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
-import { HashRouter, Routes, Route } from 'react-router-dom';
+import { useEffect, useState } from 'react';
+import { HashRouter, Routes, Route, useNavigate } from 'react-router-dom';
 import Header from './components/Layout/Header';
 import Dashboard from './pages/Dashboard';
 import ReportDetails from './pages/ReportDetails';
@@ -9,11 +10,55 @@ import Suggestions from './pages/Suggestions';
 import Reports from './pages/Reports';
 import CICD from './pages/CICD';
 import About from './pages/About';
+import * as api from './api';
 import './index.css';
 
+// Opens the run a carbonlint:// link pointed at, and asks before a link
+// starts or stops a session, whether the link launched the app or arrived
+// while it was running.
+function DeepLinkHandler() {
+  const navigate = useNavigate();
+  const [prompt, setPrompt] = useState(null);
+
+  useEffect(() => {
+    const checkPending = async () => {
+      const id = await api.takeDeepLinkRun().catch(() => null);
+      if (id) navigate(`/report/${id}`);
+      setPrompt(await api.getDeepLinkPrompt().catch(() => null));
+    };
+    checkPending();
+    const unlisten = api.onDeepLink(checkPending);
+    return () => { unlisten.then((stop) => stop()); };
+  }, [navigate]);
+
+  const answer = (accept) => {
+    setPrompt(null);
+    api.confirmDeepLink(accept).catch(() => {});
+  };
+
+  if (!prompt) return null;
+  return (
+    <div style={{
+      position: 'fixed', inset: 0, zIndex: 10000,
+      display: 'flex', alignItems: 'center', justifyContent: 'center',
+      background: 'rgba(0, 0, 0, 0.5)',
+    }}>
+      <div className="card" style={{ maxWidth: '420px' }}>
+        <h3 className="mb-md">Allow this link?</h3>
+        <p className="text-secondary mb-lg">{prompt}</p>
+        <div className="flex justify-end gap-sm">
+          <button className="btn btn-secondary" onClick={() => answer(false)}>Cancel</button>
+          <button className="btn btn-primary" onClick={() => answer(true)}>Allow</button>
+        </div>
+      </div>
+    </div>
+  );
+}
+
 function App() {
   return (
     <HashRouter>
+      <DeepLinkHandler />
       <div className="app-container">
         <Header />
         <main className="main-content">
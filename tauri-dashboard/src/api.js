@@ -1,4 +1,5 @@
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 
 /**
  * Helper to call Tauri commands
@@ -28,18 +29,34 @@ export async function getStatsSummary() {
 // Runs API
 // ============================================
 
+/**
+ * Most recent runs first. Options: limit, offset, and the filter fields
+ * project, branch, commit, command, since and until.
+ */
 export async function getRuns(options = {}) {
-    return callTauri('get_runs');
+    return callTauri('get_runs', { filter: { newestFirst: true, ...options } });
 }
 
 export async function getRun(id) {
     return callTauri('get_run', { id });
 }
 
+export async function getRunSamples(id, maxPoints) {
+    return callTauri('get_run_samples', { id, maxPoints });
+}
+
 export async function deleteRun(id) {
     return callTauri('delete_run', { id });
 }
 
+export async function recomputeRuns(options = {}) {
+    return callTauri('recompute_runs', { options });
+}
+
+export async function simulateRun(runId, scenarios) {
+    return callTauri('simulate_run', { runId, scenarios });
+}
+
 // ============================================
 // Profiling API
 // ============================================
@@ -52,8 +69,32 @@ export async function startProfiling(options = {}) {
     return callTauri('start_profiling', { options });
 }
 
-export async function stopProfiling() {
-    return callTauri('stop_profiling');
+export async function stopProfiling(session) {
+    return callTauri('stop_profiling', { session });
+}
+
+export async function pauseProfiling(session) {
+    return callTauri('pause_profiling', { session });
+}
+
+export async function resumeProfiling(session) {
+    return callTauri('resume_profiling', { session });
+}
+
+export async function markPhase(name, session) {
+    return callTauri('mark_phase', { name, session });
+}
+
+export async function getRecoveredSessions() {
+    return callTauri('get_recovered_sessions');
+}
+
+export async function finalizeRecoveredSession(id) {
+    return callTauri('finalize_recovered_session', { id });
+}
+
+export async function discardRecoveredSession(id) {
+    return callTauri('discard_recovered_session', { id });
 }
 
 // ============================================
@@ -68,6 +109,10 @@ export async function saveSettings(settings) {
     return callTauri('save_settings', { settings });
 }
 
+export async function getStorageIssues() {
+    return callTauri('get_storage_issues');
+}
+
 export async function enableAutostart(enable) {
     if (enable) {
         return invoke('plugin:autostart|enable');
@@ -92,10 +137,47 @@ export async function getCarbonIntensity() {
     return callTauri('get_carbon_intensity_data');
 }
 
+export async function getCurrentIntensity() {
+    return callTauri('get_current_intensity');
+}
+
+export async function suggestRunWindow(options) {
+    return callTauri('suggest_run_window', { options });
+}
+
+export async function saveIntensityProfile(region, profile) {
+    return callTauri('save_intensity_profile', { region, profile });
+}
+
+export async function deleteIntensityProfile(region) {
+    return callTauri('delete_intensity_profile', { region });
+}
+
 export async function getHardwareProfiles() {
     return callTauri('get_hardware_profiles_data');
 }
 
+export async function getShellHook(shell) {
+    return callTauri('get_shell_hook', { shell });
+}
+
+export async function takeDeepLinkRun() {
+    return callTauri('take_deep_link_run');
+}
+
+export async function getDeepLinkPrompt() {
+    return callTauri('get_deep_link_prompt');
+}
+
+export async function confirmDeepLink(accept) {
+    return callTauri('confirm_deep_link', { accept });
+}
+
+/** Calls `callback` whenever a deep link brings the dashboard forward; resolves to an unlisten function. */
+export function onDeepLink(callback) {
+    return listen('deep-link', callback);
+}
+
 // ============================================
 // Helpers
 // ============================================
@@ -13,7 +13,7 @@ import {
     Filler,
 } from 'chart.js';
 import { Link } from 'react-router-dom';
-import { Leaf, Zap, Battery, BarChart3, TrendingDown, TrendingUp, Play, Square, Cpu, HardDrive, History, Settings, CircleDot, Activity, ArrowRight, CheckCircle } from 'lucide-react';
+import { Leaf, Zap, Battery, BarChart3, TrendingDown, TrendingUp, Play, Pause, Square, Cpu, HardDrive, History, Settings, CircleDot, Activity, ArrowRight, CheckCircle } from 'lucide-react';
 import * as api from '../api';
 
 ChartJS.register(
@@ -37,6 +37,8 @@ export default function Dashboard() {
     const [error, setError] = useState(null);
     const [profilingTime, setProfilingTime] = useState(0);
     const [toast, setToast] = useState(null);
+    const [recovered, setRecovered] = useState([]);
+    const [storageIssues, setStorageIssues] = useState([]);
     const timerRef = useRef(null);
 
     const showToast = (message, type = 'success') => {
@@ -47,14 +49,18 @@ export default function Dashboard() {
     useEffect(() => {
         async function fetchData() {
             try {
-                const [summaryData, runsData, profilingStatus] = await Promise.all([
+                const [summaryData, runsData, profilingStatus, recoveredSessions, issues] = await Promise.all([
                     api.getStatsSummary().catch(() => ({ totalRuns: 0, totalCarbon: '0', totalEnergy: '0', trend: '0' })),
                     api.getRuns({ limit: 10 }).catch(() => []),
                     api.getProfilingStatus().catch(() => ({ active: false })),
+                    api.getRecoveredSessions().catch(() => []),
+                    api.getStorageIssues().catch(() => []),
                 ]);
                 setSummary(summaryData);
                 setRuns(runsData);
                 setProfiling(profilingStatus);
+                setRecovered(recoveredSessions);
+                setStorageIssues(issues);
                 setLoading(false);
             } catch (err) {
                 setError('Cannot connect to API. Run: node server/index.js');
@@ -86,18 +92,18 @@ export default function Dashboard() {
     }, []);
 
     useEffect(() => {
-        if (profiling.active) {
+        if (profiling.active && !profiling.paused) {
             timerRef.current = setInterval(() => setProfilingTime(t => t + 1), 1000);
         } else {
             if (timerRef.current) clearInterval(timerRef.current);
-            setProfilingTime(0);
+            if (!profiling.active) setProfilingTime(0);
         }
         return () => { if (timerRef.current) clearInterval(timerRef.current); };
-    }, [profiling.active]);
+    }, [profiling.active, profiling.paused]);
 
     const handleStartProfiling = async () => {
         try {
-            await api.startProfiling({ project: 'CarbonLint Dashboard', command: 'Live monitoring' });
+            await api.startProfiling({ command: 'Live monitoring' });
             setProfiling({ active: true });
             showToast('⚡ Profiling started! Recording system metrics...');
         } catch (err) {
@@ -105,6 +111,19 @@ export default function Dashboard() {
         }
     };
 
+    const handleTogglePause = async () => {
+        try {
+            if (profiling.paused) {
+                await api.resumeProfiling();
+            } else {
+                await api.pauseProfiling();
+            }
+            setProfiling(p => ({ ...p, paused: !p.paused }));
+        } catch (err) {
+            showToast('Failed to pause: ' + err.message, 'error');
+        }
+    };
+
     const handleStopProfiling = async () => {
         try {
             const result = await api.stopProfiling();
@@ -121,6 +140,26 @@ export default function Dashboard() {
         }
     };
 
+    const handleRecoveredSession = async (id, finalize) => {
+        try {
+            if (finalize) {
+                await api.finalizeRecoveredSession(id);
+                const [runsData, summaryData] = await Promise.all([
+                    api.getRuns({ limit: 10 }),
+                    api.getStatsSummary(),
+                ]);
+                setRuns(runsData);
+                setSummary(summaryData);
+                showToast('✅ Recovered session saved as a run');
+            } else {
+                await api.discardRecoveredSession(id);
+            }
+            setRecovered(sessions => sessions.filter(s => s.id !== id));
+        } catch (err) {
+            showToast('Failed to recover session: ' + err.message, 'error');
+        }
+    };
+
     const trendData = runs.slice().reverse();
     const trendChartData = {
         labels: trendData.map((_, i) => `${i + 1}`),
@@ -204,7 +243,10 @@ export default function Dashboard() {
                 <div className="flex gap-sm items-center">
                     {profiling.active ? (
                         <>
-                            <span className="badge badge-high flex items-center gap-xs"><CircleDot size={14} /> Recording: {profilingTime}s</span>
+                            <span className="badge badge-high flex items-center gap-xs"><CircleDot size={14} /> {profiling.paused ? 'Paused' : 'Recording'}: {profilingTime}s</span>
+                            <button className="btn btn-secondary flex items-center gap-xs" onClick={handleTogglePause}>
+                                {profiling.paused ? <><Play size={16} /> Resume</> : <><Pause size={16} /> Pause</>}
+                            </button>
                             <button className="btn btn-primary flex items-center gap-xs" onClick={handleStopProfiling}><Square size={16} /> Stop</button>
                         </>
                     ) : (
@@ -213,6 +255,37 @@ export default function Dashboard() {
                 </div>
             </div>
 
+            {recovered.map(session => (
+                <div key={session.id} className="card mb-lg" style={{ borderColor: '#F97316', borderWidth: '1px' }}>
+                    <div className="flex justify-between items-center flex-wrap gap-sm">
+                        <div>
+                            <strong>Interrupted session: {session.command}</strong>
+                            <p className="text-secondary">
+                                {session.project} · started {new Date(session.startTime).toLocaleString()} · {session.sampleCount} samples up to {new Date(session.checkpointedAt).toLocaleTimeString()}
+                            </p>
+                        </div>
+                        <div className="flex gap-sm">
+                            <button className="btn btn-primary" onClick={() => handleRecoveredSession(session.id, true)}>Save as run</button>
+                            <button className="btn btn-secondary" onClick={() => handleRecoveredSession(session.id, false)}>Discard</button>
+                        </div>
+                    </div>
+                </div>
+            ))}
+
+            {storageIssues.length > 0 && (
+                <div className="card mb-lg" style={{ borderColor: '#EF4444', borderWidth: '1px' }}>
+                    <strong>Some stored data could not be read</strong>
+                    <p className="text-secondary">The affected files and rows were left untouched so nothing is lost.</p>
+                    <ul className="text-secondary" style={{ marginTop: '0.5rem', paddingLeft: '1.25rem' }}>
+                        {storageIssues.map((issue, i) => (
+                            <li key={i}>
+                                <span style={{ fontFamily: 'monospace' }}>{issue.source}{issue.id ? ` · ${issue.id}` : ''}</span>: {issue.message}
+                            </li>
+                        ))}
+                    </ul>
+                </div>
+            )}
+
             {liveStats && (
                 <div className="card mb-lg" style={{ borderColor: '#22C55E', borderWidth: '1px' }}>
                     <div className="flex gap-xl items-center flex-wrap">
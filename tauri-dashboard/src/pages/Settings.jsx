@@ -97,6 +97,11 @@ export default function Settings() {
                                     </select>
                                     <p className="text-secondary text-sm mt-sm">Determines power consumption estimates for your hardware type</p>
                                 </div>
+                                <div>
+                                    <label className="text-secondary text-sm mb-sm" style={{ display: 'block' }}>Project Directory</label>
+                                    <input type="text" className="input" placeholder="/path/to/your/repository" value={settings.projectDirectory || ''} onChange={(e) => updateSetting('projectDirectory', e.target.value || null)} />
+                                    <p className="text-secondary text-sm mt-sm">Project, branch and commit of manual sessions are read from this git repository</p>
+                                </div>
                                 <div className="flex justify-between items-center">
                                     <div>
                                         <div className="font-medium">Start on Boot</div>
@@ -107,6 +112,15 @@ export default function Settings() {
                                         await api.enableAutostart(v);
                                     }} />
                                 </div>
+                                <div className="flex justify-between items-center">
+                                    <div>
+                                        <div className="font-medium">Profile Builds Automatically</div>
+                                        <div className="text-secondary text-sm">
+                                            Record a run whenever {(settings.processWatch?.rules || []).map(r => r.pattern).join(', ') || 'a watched process'} starts. Figures cover the whole machine while it runs
+                                        </div>
+                                    </div>
+                                    <Toggle checked={settings.processWatch?.enabled || false} onChange={(v) => updateSetting('processWatch', { ...settings.processWatch, enabled: v })} />
+                                </div>
                                 <div>
                                     <label className="text-secondary text-sm mb-sm" style={{ display: 'block' }}>Daily Carbon Budget (gCO2)</label>
                                     <input type="number" className="input" style={{ width: '200px' }} value={settings.dailyCarbonBudget || 50} onChange={(e) => updateSetting('dailyCarbonBudget', parseInt(e.target.value))} />